@@ -1,8 +1,7 @@
 #![deny(clippy::perf, clippy::correctness)]
 #![warn(rust_2018_idioms, clippy::complexity, clippy::nursery)]
 
-use glitch_core::eval::EvalContext;
-use glitch_core::Token;
+use glitch_core::{ColorSpace, EdgeMode, NumericMode, Pipeline, StateMode, Token};
 use clap::Parser;
 use console::{style, Emoji};
 use dirs::home_dir;
@@ -10,12 +9,9 @@ use gif::{Encoder, Repeat};
 use image::codecs::gif::GifDecoder;
 use image::codecs::webp::WebPDecoder;
 use image::{
-    guess_format, AnimationDecoder, DynamicImage, Frame, GenericImage, GenericImageView,
-    ImageDecoder, ImageFormat, Pixel, RgbaImage,
+    guess_format, AnimationDecoder, DynamicImage, Frame, ImageDecoder, ImageFormat,
 };
 use indicatif::{ProgressBar, ProgressStyle};
-use rand::prelude::StdRng;
-use rand::{RngCore, SeedableRng};
 use rayon::prelude::*;
 use std::fs;
 use std::fs::File;
@@ -23,7 +19,6 @@ use std::hash::{DefaultHasher, Hash, Hasher};
 use std::io::{BufRead, BufReader, BufWriter, Read, Write};
 use std::iter::Filter;
 use std::path::{Path, PathBuf};
-use std::sync::Mutex;
 use std::time::Duration;
 use webp_animation::EncoderOptions;
 
@@ -43,10 +38,30 @@ struct Args {
     #[arg(long, default_value = "false")]
     open: bool,
 
-    /// Disable the state during processing
+    /// Disable the state during processing (same as `--state off`)
     #[arg(long, default_value = "false")]
     no_state: bool,
 
+    /// How `s` carries the previous pixel: scan (column by column, like go-glitch), columns or off
+    #[arg(long, default_value = "scan")]
+    state: StateMode,
+
+    /// Leave fully transparent pixels transparent black instead of evaluating them
+    #[arg(long, default_value = "false")]
+    skip_transparent: bool,
+
+    /// How pixels outside the image are read: zero, clamp, wrap or mirror
+    #[arg(long, default_value = "zero")]
+    edge: EdgeMode,
+
+    /// Colour space expressions run in: rgb, hsv, ycbcr or lab (`use <space>;` overrides it)
+    #[arg(long, default_value = "rgb")]
+    space: ColorSpace,
+
+    /// How intermediate values are carried: byte, wide or saturate (`use <mode>;` overrides it)
+    #[arg(long, default_value = "byte")]
+    numeric: NumericMode,
+
     /// Enable verbose output
     #[arg(short, long, default_value = "false")]
     verbose: bool,
@@ -78,6 +93,9 @@ struct Args {
     expression_file: Option<PathBuf>,
 }
 
+/// Bumped whenever the serialized token format changes, so stale caches are ignored.
+const CACHE_VERSION: u32 = 13;
+
 static LOOKING_GLASS: Emoji<'_, '_> = Emoji("🔍  ", "");
 static DOWNLOAD: Emoji<'_, '_> = Emoji("🌍  ", "");
 static IMAGE: Emoji<'_, '_> = Emoji("🗃️  ", "");
@@ -177,9 +195,8 @@ fn main() -> anyhow::Result<()> {
             style(&args.expressions.len()).bold().cyan(),
             if args.expressions.len() > 1 { "s" } else { "" }
         );
-        let mut idx = 1;
         let expression_count = args.expressions.len();
-        for e in &args.expressions {
+        for (idx, e) in (1..).zip(args.expressions.iter()) {
             let spinner = ProgressBar::new_spinner();
             spinner.set_style(
                 ProgressStyle::with_template("{prefix:.bold.dim} {spinner} {wide_msg}")?
@@ -193,9 +210,9 @@ fn main() -> anyhow::Result<()> {
             ));
             spinner.enable_steady_tick(Duration::from_millis(100));
 
-            let tokens = match glitch_core::parser::shunting_yard(e) {
-                Ok(tokens) => tokens,
-                Err(err) => {
+            let tokens = match glitch_core::verify(e) {
+                Ok(result) => result.tokens,
+                Err(errors) => {
                     spinner.finish_and_clear();
 
                     println!(
@@ -203,7 +220,14 @@ fn main() -> anyhow::Result<()> {
                         ERROR,
                         style(e).bold().cyan()
                     );
-                    println!("{} {} -> {}", ERROR, style("ERROR").red().bold(), err);
+                    for err in errors {
+                        println!(
+                            "{} {} -> {}",
+                            ERROR,
+                            style("ERROR").red().bold(),
+                            err.render(e)
+                        );
+                    }
                     return Ok(());
                 }
             };
@@ -219,13 +243,19 @@ fn main() -> anyhow::Result<()> {
             );
 
             if args.verbose {
+                if let Ok(ast) = glitch_core::ast::Ast::parse(e) {
+                    println!("\t{} {}", style("Read as").dim(), style(ast).cyan());
+                }
+                println!(
+                    "\t{} {} tokens",
+                    style("Optimized to").dim(),
+                    style(glitch_core::optimize(&tokens, args.numeric).len()).cyan()
+                );
                 tokens.iter().for_each(|t| {
                     println!("\t{}", t);
                 });
             }
 
-            idx += 1;
-
             parsed.push((e.to_string(), tokens));
         }
 
@@ -309,7 +339,7 @@ fn handle_image(args: &Args, parsed: &[(String, Vec<Token>)]) -> anyhow::Result<
                 style("PNG").bold().cyan()
             );
 
-            let out = process(img, parsed, args, Some(ProgressBar::new(0)))?;
+            let out = render(img, parsed, args)?;
             out.save_with_format(output.clone(), format)?;
         }
         ImageFormat::Jpeg => {
@@ -321,7 +351,7 @@ fn handle_image(args: &Args, parsed: &[(String, Vec<Token>)]) -> anyhow::Result<
                 style("JPEG").bold().cyan()
             );
 
-            let out = process(img, parsed, args, Some(ProgressBar::new(0)))?;
+            let out = render(img, parsed, args)?;
             out.save_with_format(output.clone(), format)?;
         }
         ImageFormat::WebP => {
@@ -346,29 +376,16 @@ fn handle_image(args: &Args, parsed: &[(String, Vec<Token>)]) -> anyhow::Result<
                     .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta}) {msg}")?
             );
 
-            let new_frames = Mutex::new(Vec::with_capacity(frames.len()));
-            (0..frames.len()).into_par_iter().for_each(|i| {
-                let pb = multi_progress.add(ProgressBar::new(0));
-                pb.enable_steady_tick(Duration::from_millis(100));
-
-                let frame = frames.get(i).expect("Failed to get frame").to_owned();
-                let delay = frame.delay().numer_denom_ms().0;
-
-                let img = frame.into_buffer();
-                let out =
-                    process(img.into(), parsed, args, Some(pb)).expect("Failed to process frame");
-
-                let frame = Frame::new(RgbaImage::from(out));
-                new_frames
-                    .lock()
-                    .expect("failed to unlock")
-                    .push((i, (frame, delay)));
-
-                frames_spin.inc(1);
-            });
-
-            let mut frames = new_frames.into_inner().expect("Failed to get frames");
-            frames.sort_by(|a, b| a.0.cmp(&b.0));
+            let outputs = render_frames(&frames, parsed, args, &frames_spin)?;
+            let frames: Vec<_> = outputs
+                .into_iter()
+                .zip(&frames)
+                .map(|(out, frame)| {
+                    let delay = frame.delay().numer_denom_ms().0;
+                    (Frame::new(out.into_rgba8()), delay)
+                })
+                .enumerate()
+                .collect();
 
             frames_spin.reset();
             frames_spin.set_length(frames.len() as u64);
@@ -411,8 +428,6 @@ fn handle_image(args: &Args, parsed: &[(String, Vec<Token>)]) -> anyhow::Result<
             let mut encoder = Encoder::new(&mut img_writer, w as u16, h as u16, &[])?;
             encoder.set_repeat(Repeat::Infinite)?;
 
-            let new_frames = Mutex::new(Vec::with_capacity(frames.len()));
-
             let frame_count = frames.len();
             println!(
                 "{} Processing mode: 󰸭 {} with {} frames",
@@ -427,36 +442,26 @@ fn handle_image(args: &Args, parsed: &[(String, Vec<Token>)]) -> anyhow::Result<
                     .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta}) {msg}")?
             );
 
-            (0..frame_count).into_par_iter().for_each(|i| {
-                let pb = multi_progress.add(ProgressBar::new(0));
-                // update a bit slower
-                pb.enable_steady_tick(Duration::from_millis(100));
-
-                let frame = frames.get(i).expect("Failed to get frame").to_owned();
-                let delay = frame.delay().numer_denom_ms().0 as u16;
-                let img = frame.into_buffer();
-                let out =
-                    process(img.into(), parsed, args, Some(pb)).expect("Failed to process frame");
-                let mut bytes = out.as_bytes().to_vec();
-
-                let mut new_frame = gif::Frame::from_rgba_speed(w as u16, h as u16, &mut bytes, 10);
-
-                new_frame.delay = delay / 10;
-                new_frames
-                    .lock()
-                    .expect("failed to unlock")
-                    .push((i, new_frame));
-
-                frames_spin.inc(1);
-            });
+            let outputs = render_frames(&frames, parsed, args, &frames_spin)?;
+            let new_frames: Vec<gif::Frame<'_>> = outputs
+                .into_par_iter()
+                .zip(frames.par_iter())
+                .map(|(out, frame)| {
+                    let delay = frame.delay().numer_denom_ms().0 as u16;
+                    let mut bytes = out.as_bytes().to_vec();
+
+                    let mut new_frame =
+                        gif::Frame::from_rgba_speed(w as u16, h as u16, &mut bytes, 10);
+                    new_frame.delay = delay / 10;
+                    new_frame
+                })
+                .collect();
 
             frames_spin.reset();
             frames_spin.set_length(frame_count as u64);
             frames_spin.set_message("Encoding frames...");
 
-            let mut frames = new_frames.into_inner().expect("Failed to get frames");
-            frames.sort_by(|a, b| a.0.cmp(&b.0));
-            for (_, frame) in frames {
+            for frame in new_frames {
                 encoder.write_frame(&frame)?;
 
                 frames_spin.inc(1);
@@ -500,90 +505,61 @@ fn handle_image(args: &Args, parsed: &[(String, Vec<Token>)]) -> anyhow::Result<
     Ok(())
 }
 
-fn process(
-    mut img: DynamicImage,
-    expressions: &[(String, Vec<Token>)],
+/// Builds the rendering pipeline for the parsed expressions from the command line options.
+fn pipeline(parsed: &[(String, Vec<Token>)], args: &Args) -> Pipeline {
+    Pipeline {
+        expressions: parsed
+            .iter()
+            .map(|(_, tokens)| glitch_core::optimize(tokens, args.numeric))
+            .collect(),
+        seed: args.seed.unwrap_or(0),
+        region: None,
+        state: if args.no_state {
+            StateMode::Off
+        } else {
+            args.state
+        },
+        edge: args.edge,
+        skip_transparent: args.skip_transparent,
+        space: args.space,
+        numeric: args.numeric,
+    }
+}
+
+/// Renders a still image, showing the evaluated pixels on a progress bar.
+fn render(
+    img: DynamicImage,
+    parsed: &[(String, Vec<Token>)],
     args: &Args,
-    progress_bar: Option<ProgressBar>,
 ) -> anyhow::Result<DynamicImage> {
-    let width = img.width();
-    let height = img.height();
-    let mut output_image = DynamicImage::new(width, height, img.color());
-
-    let pb = if let Some(pb) = progress_bar {
-        let total_pixels = ((width * height) * expressions.len() as u32) as u64;
-        pb.set_length(total_pixels);
-        pb.set_style(ProgressStyle::default_bar().template(
-            "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})",
-        )?);
-        Some(pb)
-    } else {
-        None
-    };
+    let pb = ProgressBar::new(u64::from(img.width() * img.height()) * parsed.len() as u64);
+    pb.set_style(ProgressStyle::default_bar().template(
+        "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})",
+    )?);
 
-    // Calculate bounds ONCE outside the expression loop
-    let bounds = glitch_core::bounds::find_non_zero_bounds(&img).expect("Failed to find non-zero bounds");
-    let min_x = bounds.min_x();
-    let max_x = bounds.max_x();
-    let min_y = bounds.min_y();
-    let max_y = bounds.max_y();
-
-    let seed = args.seed.unwrap_or(0);
-
-    for (_, tokens) in expressions.iter() {
-        // Generate all pixel coordinates
-        let coords: Vec<(u32, u32)> = (min_x..max_x)
-            .flat_map(|x| (min_y..max_y).map(move |y| (x, y)))
-            .collect();
-
-        // Process pixels in parallel
-        let results: Vec<(u32, u32, image::Rgba<u8>)> = coords
-            .par_iter()
-            .map(|&(x, y)| {
-                // Each thread gets its own RNG seeded deterministically
-                let mut rng: Box<dyn RngCore + Send> = Box::new(StdRng::seed_from_u64(
-                    seed.wrapping_add((x as u64) << 32 | y as u64),
-                ));
-
-                let colors = img.get_pixel(x, y).to_rgba();
-
-                let result = glitch_core::eval::eval(
-                    EvalContext {
-                        tokens,
-                        size: (width, height),
-                        rgba: colors,
-                        saved_rgb: [0, 0, 0], // Can't use state in parallel mode
-                        position: (x, y),
-                        ignore_state: true, // Must ignore state in parallel
-                    },
-                    &img,
-                    &mut rng,
-                )
-                .expect("Failed to evaluate");
-
-                (x, y, result)
-            })
-            .collect();
-
-        // Apply all results to output image
-        for (x, y, rgba) in results {
-            output_image.put_pixel(x, y, rgba);
-        }
-
-        if let Some(pb) = &pb {
-            pb.inc((max_x - min_x) as u64 * (max_y - min_y) as u64);
-        }
-
-        // Swap instead of clone - avoids full image copy
-        std::mem::swap(&mut img, &mut output_image);
-    }
+    let out = pipeline(parsed, args)
+        .render_frame(img, (0, 1), None, |pixels| pb.inc(pixels))?;
 
-    if let Some(pb) = pb {
-        pb.finish_and_clear();
-    }
+    pb.finish_and_clear();
+    Ok(out)
+}
 
-    // After swap, img has the final result
-    Ok(img)
+/// Renders every frame of an animation, showing the evaluated pixels on `frames_spin`.
+fn render_frames(
+    frames: &[Frame],
+    parsed: &[(String, Vec<Token>)],
+    args: &Args,
+    frames_spin: &ProgressBar,
+) -> anyhow::Result<Vec<DynamicImage>> {
+    let images: Vec<DynamicImage> = frames.iter().map(|f| f.buffer().clone().into()).collect();
+    let pixels: u64 = images
+        .iter()
+        .map(|img| u64::from(img.width() * img.height()))
+        .sum();
+    frames_spin.set_length(pixels * parsed.len() as u64);
+    frames_spin.set_message("Rendering frames...");
+
+    Ok(pipeline(parsed, args).render_frames(images, |pixels| frames_spin.inc(pixels))?)
 }
 
 fn strip_windows_prefix(path: &Path) -> PathBuf {
@@ -605,6 +581,7 @@ fn get_random_seed(args: &Args) -> u64 {
 
 fn hash_strings(strings: Vec<String>) -> u64 {
     let mut hasher = DefaultHasher::new();
+    CACHE_VERSION.hash(&mut hasher);
     for s in strings {
         s.hash(&mut hasher);
     }
@@ -0,0 +1,553 @@
+use crate::error::GlitchError;
+use crate::token::{channel_label, Func, Pragma, Token};
+use std::fmt;
+
+/// A node of an expression tree.
+///
+/// Built from the RPN tokens of the parser with [`Ast::from_tokens`], so the
+/// tree shows how precedence grouped the source.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Expr {
+    /// Any token that pushes a value: literals, operands and binding loads.
+    Value(Token),
+    /// `-a` or `~a`.
+    Prefix(Token, Box<Self>),
+    /// `a.rgb`.
+    Swizzle([u8; 3], Box<Self>),
+    /// An infix operator, e.g. `a + b` or `a lt b`.
+    Binary(Token, Box<Self>, Box<Self>),
+    /// Offset sampling `src[dx, dy]`.
+    Sample(char, Box<Self>, Box<Self>),
+    /// `if(cond, then, else)`.
+    If(Box<Self>, Box<Self>, Box<Self>),
+    /// A function call with its arguments.
+    Call(Func, Vec<Self>),
+}
+
+/// A statement before the result of an expression.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Statement {
+    /// `use <name>;`
+    Pragma(Pragma),
+    /// `name = value;`, storing into a binding slot.
+    Bind { slot: u8, value: Expr },
+    /// `r: value;`, setting one output channel.
+    Output { channel: u8, value: Expr },
+}
+
+impl Statement {
+    /// The value computed by the statement, if it computes one.
+    pub const fn value(&self) -> Option<&Expr> {
+        match self {
+            Self::Pragma(_) => None,
+            Self::Bind { value, .. } | Self::Output { value, .. } => Some(value),
+        }
+    }
+
+    pub const fn value_mut(&mut self) -> Option<&mut Expr> {
+        match self {
+            Self::Pragma(_) => None,
+            Self::Bind { value, .. } | Self::Output { value, .. } => Some(value),
+        }
+    }
+}
+
+/// A whole expression as a tree: its statements followed by the result.
+///
+/// Formatting an `Ast` prints canonical infix with only the parentheses that
+/// precedence needs, and parsing that text gives back the same tree, so the
+/// formatted text can be used to normalise and deduplicate expressions.
+///
+/// # Example
+/// ```
+/// use glitch_core::ast::Ast;
+///
+/// let ast = Ast::parse("(c+(h*2))-(x-5)").unwrap();
+/// assert_eq!(ast.to_string(), "c + h * 2 - (x - 5)");
+/// assert_eq!(Ast::parse(&ast.to_string()).unwrap(), ast);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Ast {
+    pub statements: Vec<Statement>,
+    /// Value of every channel not set by a statement; per-channel expressions
+    /// may leave it out.
+    pub result: Option<Expr>,
+    /// Binding names, indexed by slot.
+    pub names: Vec<String>,
+}
+
+impl Ast {
+    /// Parses and verifies `source` (see [`crate::verify`]) into a tree, keeping
+    /// the names of its bindings.
+    ///
+    /// # Errors
+    /// Returns the errors of [`crate::verify`].
+    pub fn parse(source: &str) -> Result<Self, Vec<GlitchError>> {
+        let verified = crate::verify(source)?;
+        let mut ast = Self::from_tokens(&verified.tokens).ok_or_else(|| {
+            vec![GlitchError::NoResult {
+                span: 0..source.len(),
+            }]
+        })?;
+        for (tok, span) in verified.tokens.iter().zip(verified.spans) {
+            if let Token::Store(slot) = tok {
+                ast.names[*slot as usize] = source[span].to_string();
+            }
+        }
+        Ok(ast)
+    }
+
+    /// Builds the tree of a list of RPN tokens, naming bindings `_a`, `_b`, ...
+    ///
+    /// Returns `None` if the tokens don't form exactly one tree per statement,
+    /// which [`crate::verify`] rules out for parsed expressions.
+    pub fn from_tokens(tokens: &[Token]) -> Option<Self> {
+        let mut stack: Vec<Expr> = Vec::new();
+        let mut statements = Vec::new();
+        let mut bindings = 0;
+
+        for tok in tokens {
+            let node = match *tok {
+                Token::Pragma(pragma) => {
+                    statements.push(Statement::Pragma(pragma));
+                    continue;
+                }
+                Token::Store(_) | Token::Output(_) => {
+                    let value = stack.pop()?;
+                    if !stack.is_empty() {
+                        return None;
+                    }
+                    statements.push(match *tok {
+                        Token::Store(slot) => {
+                            bindings = bindings.max(slot as usize + 1);
+                            Statement::Bind { slot, value }
+                        }
+                        Token::Output(channel) => Statement::Output { channel, value },
+                        _ => unreachable!(),
+                    });
+                    continue;
+                }
+                Token::Neg | Token::Not => Expr::Prefix(*tok, Box::new(stack.pop()?)),
+                Token::Swizzle(channels) => Expr::Swizzle(channels, Box::new(stack.pop()?)),
+                Token::Sample(src) => {
+                    let dy = stack.pop()?;
+                    Expr::Sample(src, Box::new(stack.pop()?), Box::new(dy))
+                }
+                Token::If => {
+                    let otherwise = stack.pop()?;
+                    let then = stack.pop()?;
+                    Expr::If(Box::new(stack.pop()?), Box::new(then), Box::new(otherwise))
+                }
+                Token::Call(func, args) => {
+                    let first = stack.len().checked_sub(args as usize)?;
+                    Expr::Call(func, stack.split_off(first))
+                }
+                _ => match crate::stack_effect(tok) {
+                    (0, 1) => Expr::Value(*tok),
+                    (2, 1) => {
+                        let rhs = stack.pop()?;
+                        Expr::Binary(*tok, Box::new(stack.pop()?), Box::new(rhs))
+                    }
+                    _ => return None,
+                },
+            };
+            stack.push(node);
+        }
+
+        let result = stack.pop();
+        if !stack.is_empty() {
+            return None;
+        }
+        Some(Self {
+            statements,
+            result,
+            names: (0..bindings).map(generated_name).collect(),
+        })
+    }
+
+    /// The RPN tokens of the tree, as the parser would emit them.
+    pub fn to_tokens(&self) -> Vec<Token> {
+        let mut tokens = Vec::new();
+        for statement in &self.statements {
+            match statement {
+                Statement::Pragma(pragma) => tokens.push(Token::Pragma(*pragma)),
+                Statement::Bind { slot, value } => {
+                    value.push_tokens(&mut tokens);
+                    tokens.push(Token::Store(*slot));
+                }
+                Statement::Output { channel, value } => {
+                    value.push_tokens(&mut tokens);
+                    tokens.push(Token::Output(*channel));
+                }
+            }
+        }
+        if let Some(result) = &self.result {
+            result.push_tokens(&mut tokens);
+        }
+        tokens
+    }
+}
+
+impl Expr {
+    /// Appends the RPN tokens of this node.
+    pub fn push_tokens(&self, tokens: &mut Vec<Token>) {
+        match self {
+            Self::Value(tok) => tokens.push(*tok),
+            Self::Prefix(op, operand) => {
+                operand.push_tokens(tokens);
+                tokens.push(*op);
+            }
+            Self::Swizzle(channels, operand) => {
+                operand.push_tokens(tokens);
+                tokens.push(Token::Swizzle(*channels));
+            }
+            Self::Binary(op, lhs, rhs) => {
+                lhs.push_tokens(tokens);
+                rhs.push_tokens(tokens);
+                tokens.push(*op);
+            }
+            Self::Sample(src, dx, dy) => {
+                dx.push_tokens(tokens);
+                dy.push_tokens(tokens);
+                tokens.push(Token::Sample(*src));
+            }
+            Self::If(cond, then, otherwise) => {
+                for arg in [cond, then, otherwise] {
+                    arg.push_tokens(tokens);
+                }
+                tokens.push(Token::If);
+            }
+            Self::Call(func, args) => {
+                args.iter().for_each(|arg| arg.push_tokens(tokens));
+                tokens.push(Token::Call(*func, args.len() as u8));
+            }
+        }
+    }
+
+    /// The operands of the node, in evaluation order.
+    pub fn children(&self) -> Vec<&Self> {
+        match self {
+            Self::Value(_) => vec![],
+            Self::Prefix(_, operand) | Self::Swizzle(_, operand) => vec![operand],
+            Self::Binary(_, lhs, rhs) | Self::Sample(_, lhs, rhs) => vec![lhs, rhs],
+            Self::If(cond, then, otherwise) => vec![cond, then, otherwise],
+            Self::Call(_, args) => args.iter().collect(),
+        }
+    }
+
+    pub fn children_mut(&mut self) -> Vec<&mut Self> {
+        match self {
+            Self::Value(_) => vec![],
+            Self::Prefix(_, operand) | Self::Swizzle(_, operand) => vec![operand],
+            Self::Binary(_, lhs, rhs) | Self::Sample(_, lhs, rhs) => vec![lhs, rhs],
+            Self::If(cond, then, otherwise) => vec![cond, then, otherwise],
+            Self::Call(_, args) => args.iter_mut().collect(),
+        }
+    }
+
+    /// Whether the node or any node below it matches `pred`.
+    pub fn any(&self, pred: &impl Fn(&Self) -> bool) -> bool {
+        pred(self) || self.children().into_iter().any(|child| child.any(pred))
+    }
+
+    /// Number of RPN tokens of the node.
+    pub fn token_count(&self) -> usize {
+        1 + self
+            .children()
+            .into_iter()
+            .map(Self::token_count)
+            .sum::<usize>()
+    }
+
+    /// Binding precedence of the node when printed; operands are tighter than any operator.
+    const fn precedence(&self) -> i32 {
+        match self {
+            Self::Binary(op, ..) | Self::Prefix(op, _) => crate::parser::operator_precedence(op).0,
+            _ => i32::MAX,
+        }
+    }
+}
+
+impl fmt::Display for Ast {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut first = true;
+        let mut separator = |f: &mut fmt::Formatter<'_>| {
+            let sep = if first { "" } else { "; " };
+            first = false;
+            f.write_str(sep)
+        };
+
+        for statement in &self.statements {
+            separator(f)?;
+            match statement {
+                Statement::Pragma(Pragma::Space(space)) => write!(f, "use {}", space.name())?,
+                Statement::Pragma(Pragma::Numeric(mode)) => write!(f, "use {}", mode.name())?,
+                Statement::Bind { slot, value } => write!(
+                    f,
+                    "{} = {}",
+                    self.names[*slot as usize],
+                    StatementValue(value, &self.names)
+                )?,
+                Statement::Output { channel, value } => write!(
+                    f,
+                    "{}: {}",
+                    channel_label(*channel),
+                    StatementValue(value, &self.names)
+                )?,
+            }
+        }
+        if let Some(result) = &self.result {
+            separator(f)?;
+            write!(f, "{}", StatementValue(result, &self.names))?;
+        }
+        // A final `r:`, `g:` or `b:` statement needs its `;` to read as a label
+        if self.result.is_none()
+            && matches!(
+                self.statements.last(),
+                Some(Statement::Output { channel: 0..=2, .. })
+            )
+        {
+            f.write_str(";")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Expr {
+    /// Prints the node as infix, naming bindings `_a`, `_b`, ...
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Infix(self, &[]).fmt(f)
+    }
+}
+
+/// An expression printed with the binding names of its [`Ast`].
+struct Infix<'a>(&'a Expr, &'a [String]);
+
+impl fmt::Display for Infix<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self(expr, names) = *self;
+        let operand = |f: &mut fmt::Formatter<'_>, expr: &Expr, parens: bool| {
+            if parens {
+                write!(f, "({})", Infix(expr, names))
+            } else {
+                write!(f, "{}", Infix(expr, names))
+            }
+        };
+
+        match expr {
+            Expr::Value(tok) => write_value(f, tok, names),
+            Expr::Prefix(op, rhs) => {
+                f.write_str(if *op == Token::Neg { "-" } else { "~" })?;
+                operand(f, rhs, matches!(**rhs, Expr::Binary(..)))
+            }
+            Expr::Swizzle(channels, lhs) => {
+                operand(f, lhs, matches!(**lhs, Expr::Binary(..) | Expr::Prefix(..)))?;
+                let [r, g, b] = channels.map(|c| b"rgb"[c as usize] as char);
+                if r == g && g == b {
+                    write!(f, ".{}", r)
+                } else {
+                    write!(f, ".{}{}{}", r, g, b)
+                }
+            }
+            // Operators of equal precedence group from the left
+            Expr::Binary(op, lhs, rhs) => {
+                let precedence = expr.precedence();
+                operand(f, lhs, lhs.precedence() < precedence)?;
+                write!(f, " {} ", operator_symbol(op))?;
+                operand(
+                    f,
+                    rhs,
+                    rhs.precedence() <= precedence && matches!(**rhs, Expr::Binary(..)),
+                )
+            }
+            Expr::Sample(src, dx, dy) => {
+                write!(f, "{}[{}, {}]", src, Infix(dx, names), Infix(dy, names))
+            }
+            Expr::If(cond, then, otherwise) => write!(
+                f,
+                "if({}, {}, {})",
+                Infix(cond, names),
+                Infix(then, names),
+                Infix(otherwise, names)
+            ),
+            Expr::Call(func, args) => {
+                write!(f, "{}(", func.name())?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}", Infix(arg, names))?;
+                }
+                f.write_str(")")
+            }
+        }
+    }
+}
+
+/// The value of a statement, parenthesised where it would otherwise read as a
+/// channel label (`g : h` is `g: h`).
+struct StatementValue<'a>(&'a Expr, &'a [String]);
+
+impl fmt::Display for StatementValue<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = Infix(self.0, self.1).to_string();
+        let word_end = text
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(text.len());
+        let is_label = matches!(&text[..word_end], "r" | "g" | "b" | "a")
+            && text[word_end..].trim_start().starts_with(':');
+        if is_label {
+            write!(f, "({})", text)
+        } else {
+            f.write_str(&text)
+        }
+    }
+}
+
+fn write_value(f: &mut fmt::Formatter<'_>, tok: &Token, names: &[String]) -> fmt::Result {
+    match *tok {
+        Token::Num(n) => write!(f, "{}", n),
+        Token::Float(bits) => {
+            // Whole floats need their `.0` to stay floats
+            let text = f32::from_bits(bits).to_string();
+            if text.contains('.') {
+                f.write_str(&text)
+            } else {
+                write!(f, "{}.0", text)
+            }
+        }
+        Token::Random(range) => write!(f, "r{}", range),
+        Token::Brightness(value) => write!(f, "b{}", value),
+        Token::Blur(radius) => write!(f, "k{}", radius),
+        Token::RGBColor((part, value)) => write!(f, "{}{}", part, value),
+        Token::Invert => f.write_str("i"),
+        Token::Char(ch) => write!(f, "{}", ch),
+        Token::Param(param) => f.write_str(param.name()),
+        Token::Load(slot) => match names.get(slot as usize) {
+            Some(name) => f.write_str(name),
+            None => f.write_str(&generated_name(slot as usize)),
+        },
+        _ => write!(f, "{:?}", tok),
+    }
+}
+
+const fn operator_symbol(op: &Token) -> &'static str {
+    match op {
+        Token::Add => "+",
+        Token::Sub => "-",
+        Token::Mul => "*",
+        Token::Div => "/",
+        Token::Mod => "%",
+        Token::Pow => "#",
+        Token::BitAnd => "&",
+        Token::BitOr => "|",
+        Token::BitXor => "^",
+        Token::BitAndNot => ":",
+        Token::BitLShift => "<",
+        Token::BitRShift => ">",
+        Token::Greater => "gt",
+        Token::Less => "lt",
+        Token::GreaterEqual => "ge",
+        Token::LessEqual => "le",
+        Token::Equal => "eq",
+        Token::NotEqual => "ne",
+        Token::Min => "min",
+        Token::Max => "max",
+        Token::Weight => "@",
+        _ => "?",
+    }
+}
+
+/// Name of binding `slot` when the source isn't known: `_a` to `_z`, then `_aa`, ...
+pub(crate) fn generated_name(slot: usize) -> String {
+    let mut letters = Vec::new();
+    let mut n = slot + 1;
+    while n > 0 {
+        n -= 1;
+        letters.push(b'a' + (n % 26) as u8);
+        n /= 26;
+    }
+    letters.reverse();
+    format!("_{}", String::from_utf8_lossy(&letters))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::shunting_yard;
+
+    fn format(source: &str) -> String {
+        Ast::parse(source).unwrap().to_string()
+    }
+
+    #[test]
+    fn test_minimal_parens() {
+        assert_eq!(format("(c + h) * 2"), "(c + h) * 2");
+        assert_eq!(format("c + (h * 2)"), "c + h * 2");
+        assert_eq!(format("(c - h) - x"), "c - h - x");
+        assert_eq!(format("c - (h - x)"), "c - (h - x)");
+        // Comparisons bind tighter than `*`
+        assert_eq!(format("(c ? 128) * 255"), "c gt 128 * 255");
+        assert_eq!(format("c * (h ? 5)"), "c * h gt 5");
+        assert_eq!(format("-(c+h)"), "-(c + h)");
+        assert_eq!(format("(-c).r + -h.gbr"), "(-c).r + -h.gbr");
+        assert_eq!(format("(c+h).rgb*2"), "(c + h).rgb * 2");
+    }
+
+    #[test]
+    fn test_statements() {
+        assert_eq!(
+            format("use lab;edge=abs(c-h);r:edge@128;g:(g:h);"),
+            "use lab; edge = abs(c - h); r: edge @ 128; g: (g : h);"
+        );
+        assert_eq!(format("(g : h) ^ 2"), "(g : h ^ 2)");
+        assert_eq!(
+            format("if(c lt 9,h[1,-2],min(h,v,d)) min 0.5"),
+            "if(c lt 9, h[1, -2], min(h, v, d)) min 0.5"
+        );
+        assert_eq!(
+            format("r3 + R0 + b + k + i + 2.0"),
+            "r3 + R0 + b255 + k1 + i + 2.0"
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let sources = include_str!("../../tests/line_art_mirrored.txt")
+            .lines()
+            .chain([
+                "a = c ^ 55; a = a + 1; (a & s) + a",
+                "~-c.r - --h",
+                "c # 2 # 3 % (4 # 5)",
+                "c < 2 > 1 lt 3 le 4 ge 5 eq 6 ne 7",
+                "use float; use hsv; r: c; b: dist * T;",
+                "hsv(c.r, 0.25, 0.125) + p[frame, -1]",
+                "(g : h)",
+                "g: h;",
+            ])
+            .filter(|line| !line.trim().is_empty());
+
+        for source in sources {
+            let ast = Ast::parse(source).unwrap();
+            let formatted = ast.to_string();
+            assert_eq!(
+                Ast::parse(&formatted).unwrap(),
+                ast,
+                "{source} -> {formatted}"
+            );
+            assert_eq!(ast.to_tokens(), shunting_yard(source).unwrap(), "{source}");
+        }
+    }
+
+    #[test]
+    fn test_generated_names() {
+        let tokens = shunting_yard("first = c; second = h; first ^ second").unwrap();
+        let ast = Ast::from_tokens(&tokens).unwrap();
+        assert_eq!(ast.to_string(), "_a = c; _b = h; _a ^ _b");
+        assert_eq!(generated_name(26), "_aa");
+
+        // Not one tree
+        assert_eq!(Ast::from_tokens(&[Token::Num(1), Token::Num(2)]), None);
+        assert_eq!(Ast::from_tokens(&[Token::Add]), None);
+    }
+}
@@ -0,0 +1,151 @@
+use crate::edge::EdgeMode;
+use crate::rgb::Rgb;
+use crate::space::ColorSpace;
+use image::{DynamicImage, GenericImageView};
+
+/// Summed-area table of an image, so the box average of `k<n>` costs four reads
+/// whatever the radius.
+///
+/// The table covers the image padded by the widest radius it was built for, with
+/// the padding read through the edge mode, so boxes reaching past the edges
+/// average the same pixels as reading them one by one would.
+#[derive(Debug, Clone)]
+pub struct BlurTable {
+    radius: u32,
+    /// Width of a row of `sums`.
+    stride: usize,
+    /// Sum of every padded pixel above and to the left of each entry. Sums wrap
+    /// at `u32`, which the differences of `mean` undo as no box reaches 2^32.
+    sums: Vec<[u32; 3]>,
+}
+
+impl BlurTable {
+    /// Builds the table for boxes up to `radius`, reading `input` in `space`.
+    pub fn new(input: &DynamicImage, edge: EdgeMode, space: ColorSpace, radius: u8) -> Self {
+        let (width, height) = input.dimensions();
+        let radius = u32::from(radius);
+        let pixels: Vec<[u8; 3]> = input
+            .pixels()
+            .map(|(_, _, p)| space.from_rgb([p[0], p[1], p[2]]))
+            .collect();
+        let outside = space.from_rgb([0, 0, 0]);
+
+        let (padded_width, padded_height) = (width + 2 * radius, height + 2 * radius);
+        let stride = padded_width as usize + 1;
+        let mut sums = vec![[0u32; 3]; stride * (padded_height as usize + 1)];
+        for py in 0..padded_height {
+            let mut row = [0u32; 3];
+            for px in 0..padded_width {
+                let x = i64::from(px) - i64::from(radius);
+                let y = i64::from(py) - i64::from(radius);
+                let pixel = edge
+                    .resolve(x, y, width, height)
+                    .map_or(outside, |(x, y)| pixels[(y * width + x) as usize]);
+                let i = (py as usize + 1) * stride + px as usize + 1;
+                for c in 0..3 {
+                    row[c] = row[c].wrapping_add(u32::from(pixel[c]));
+                    sums[i][c] = sums[i - stride][c].wrapping_add(row[c]);
+                }
+            }
+        }
+
+        Self {
+            radius,
+            stride,
+            sums,
+        }
+    }
+
+    /// Average of the box of `radius` around (x, y), or `None` if the table
+    /// wasn't built for a radius that wide.
+    pub fn mean(&self, (x, y): (u32, u32), radius: u8) -> Option<Rgb> {
+        let radius = u32::from(radius);
+        if radius > self.radius {
+            return None;
+        }
+        // Corners of the box in the padded image, `to` being exclusive
+        let (from_x, from_y) = (x + self.radius - radius, y + self.radius - radius);
+        let (to_x, to_y) = (from_x + 2 * radius + 1, from_y + 2 * radius + 1);
+        let at = |x: u32, y: u32| self.sums[y as usize * self.stride + x as usize];
+        let (a, b, c, d) = (
+            at(from_x, from_y),
+            at(to_x, from_y),
+            at(from_x, to_y),
+            at(to_x, to_y),
+        );
+
+        let count = (2 * radius + 1) * (2 * radius + 1);
+        Some(Rgb::from([0, 1, 2].map(|i| {
+            let sum = d[i]
+                .wrapping_sub(b[i])
+                .wrapping_sub(c[i])
+                .wrapping_add(a[i]);
+            (sum / count) as u8
+        })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+
+    /// Averages the box by reading every pixel in it.
+    fn naive(input: &DynamicImage, edge: EdgeMode, (x, y): (u32, u32), radius: u8) -> Rgb {
+        let radius = i64::from(radius);
+        let (x, y) = (i64::from(x), i64::from(y));
+        let mut sum = [0u32; 3];
+        for j in y - radius..=y + radius {
+            for i in x - radius..=x + radius {
+                let pixel = edge
+                    .resolve(i, j, input.width(), input.height())
+                    .map_or([0; 4], |(i, j)| input.get_pixel(i, j).0);
+                for c in 0..3 {
+                    sum[c] += u32::from(pixel[c]);
+                }
+            }
+        }
+        let count = ((2 * radius + 1) * (2 * radius + 1)) as u32;
+        Rgb::from(sum.map(|s| (s / count) as u8))
+    }
+
+    #[test]
+    fn test_matches_reading_every_pixel() {
+        let img: DynamicImage = RgbaImage::from_fn(7, 5, |x, y| {
+            Rgba([
+                (x * 37 + y * 91) as u8,
+                (x * y * 13) as u8,
+                255 - (y * 50) as u8,
+                255,
+            ])
+        })
+        .into();
+
+        for edge in [
+            EdgeMode::Zero,
+            EdgeMode::Clamp,
+            EdgeMode::Wrap,
+            EdgeMode::Mirror,
+        ] {
+            let table = BlurTable::new(&img, edge, ColorSpace::Rgb, 255);
+            // Radii from one pixel to boxes far larger than the image
+            for radius in [1, 2, 6, 40, 255] {
+                for (x, y, _) in img.pixels() {
+                    assert_eq!(
+                        table.mean((x, y), radius),
+                        Some(naive(&img, edge, (x, y), radius)),
+                        "{edge:?} k{radius} at ({x}, {y})"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_wider_radius_than_built() {
+        let img: DynamicImage = RgbaImage::from_pixel(3, 3, Rgba([9, 9, 9, 255])).into();
+        let table = BlurTable::new(&img, EdgeMode::Clamp, ColorSpace::Rgb, 2);
+        assert_eq!(table.mean((1, 1), 2), Some(Rgb::new(9, 9, 9)));
+        assert_eq!(table.mean((1, 1), 3), None);
+    }
+}
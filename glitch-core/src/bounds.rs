@@ -21,6 +21,16 @@ impl Bounds {
         }
     }
 
+    /// Creates bounds covering `min_x..=max_x` and `min_y..=max_y`.
+    pub const fn from_corners((min_x, min_y): (u32, u32), (max_x, max_y): (u32, u32)) -> Self {
+        Self {
+            min_x,
+            min_y,
+            max_x,
+            max_y,
+        }
+    }
+
     /// Updates the bounds based on the x, y coordinates provided.
     const fn update(&mut self, x: u32, y: u32) {
         if x < self.min_x {
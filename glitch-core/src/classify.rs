@@ -1,5 +1,6 @@
+use crate::error::GlitchError;
 use crate::parser::shunting_yard;
-use crate::token::Token;
+use crate::token::{Func, Param, Token};
 use serde::{Deserialize, Serialize};
 
 /// Per-category classification scores for a glitch expression.
@@ -54,7 +55,7 @@ struct RawScores {
 /// requiring any image data or evaluation context.
 ///
 /// # Errors
-/// Returns the parser error if the expression has invalid syntax.
+/// Returns the parser errors if the expression has invalid syntax.
 ///
 /// # Example
 /// ```
@@ -63,14 +64,18 @@ struct RawScores {
 /// assert!(c.edge > 0.5);
 /// assert_eq!(c.noise, 0.0);
 /// ```
-pub fn classify(expr: &str) -> Result<Classification, String> {
+pub fn classify(expr: &str) -> Result<Classification, Vec<GlitchError>> {
     let tokens = shunting_yard(expr)?;
     if tokens.is_empty() {
-        return Err("Expression is empty".to_string());
+        return Err(vec![GlitchError::NoResult { span: 0..expr.len() }]);
     }
 
     // --- Phase 1: Collect interaction metadata ---
-    let has_coords = tokens.iter().any(|t| matches!(t, Token::Char('x' | 'y')));
+    let has_coords = tokens.iter().any(|t| match t {
+        Token::Char('x' | 'y') => true,
+        Token::Param(param) => param.is_positional(),
+        _ => false,
+    });
 
     // Count distinct pixel sources for blending detection.
     let source_count = count_distinct_sources(&tokens);
@@ -121,7 +126,9 @@ fn count_distinct_sources(tokens: &[Token]) -> usize {
     for tok in tokens {
         let key: Option<&str> = match tok {
             Token::Char('c') => Some("c"),
+            Token::Char('A') => Some("A"),
             Token::Char('s') => Some("s"),
+            Token::Char('p') | Token::Sample('p') => Some("p"),
             Token::Char('e') => Some("e"),
             Token::Char('h') => Some("h"),
             Token::Char('v') => Some("v"),
@@ -134,9 +141,12 @@ fn count_distinct_sources(tokens: &[Token]) -> usize {
             Token::Char('Y') => Some("Y"),
             Token::Char('x') => Some("x"),
             Token::Char('y') => Some("y"),
+            Token::Param(param) if param.is_positional() => Some(param.name()),
             Token::Random(_) => Some("r"),
+            Token::Sample(_) => Some("[]"),
             Token::RGBColor(_) => Some("RGB"),
             Token::Brightness(_) => Some("b"),
+            Token::Blur(_) => Some("k"),
             Token::Invert => Some("i"),
             _ => None,
         };
@@ -164,7 +174,7 @@ fn accumulate_token(
     match tok {
         // ── Values ──────────────────────────────────────────────────────
 
-        Token::Num(_) => {
+        Token::Num(_) | Token::Float(_) => {
             // Constants contribute mildly to brightness when combined with
             // arithmetic, but that interaction is hard to detect statically.
             // We give a small baseline.
@@ -179,10 +189,18 @@ fn accumulate_token(
             raw.feedback += 1.0;
         }
 
+        Token::Char('p') => {
+            raw.feedback += 1.0;
+        }
+
         Token::Char('Y') => {
             raw.channel += 0.7;
         }
 
+        Token::Char('A') => {
+            raw.channel += 0.8;
+        }
+
         Token::Char('x') => {
             raw.spatial += 1.0;
             raw.pattern += 0.8;
@@ -193,6 +211,26 @@ fn accumulate_token(
             raw.pattern += 0.8;
         }
 
+        Token::Param(Param::Dist | Param::PixelX | Param::PixelY) => {
+            raw.spatial += 1.0;
+            raw.pattern += 0.8;
+        }
+
+        Token::Param(Param::Angle) => {
+            raw.spatial += 1.0;
+            raw.pattern += 1.0;
+        }
+
+        Token::Param(Param::Width | Param::Height) => {
+            // Constants, but almost always used to build positional ramps.
+            raw.spatial += 0.3;
+            raw.pattern += 0.3;
+        }
+
+        Token::Param(Param::Frame | Param::Frames | Param::Time) => {
+            // Animates other effects over time, no category of its own.
+        }
+
         Token::Char('e') => {
             raw.edge += 1.0;
             raw.morphological += 0.4;
@@ -261,11 +299,28 @@ fn accumulate_token(
             raw.channel += 1.0;
         }
 
+        Token::Sample('p') => {
+            // Offset reads of the previous frame smear it like a datamosh.
+            raw.feedback += 1.0;
+            raw.displacement += 1.0;
+            raw.spatial += 0.4;
+        }
+
+        Token::Sample(_) => {
+            raw.displacement += 1.0;
+            raw.spatial += 0.4;
+        }
+
         Token::Brightness(_) => {
             raw.brightness += 1.0;
             raw.contrast += 0.5;
         }
 
+        Token::Blur(_) => {
+            raw.blur += 1.0;
+            raw.morphological += 0.2;
+        }
+
         Token::Invert => {
             raw.contrast += 0.7;
             raw.channel += 0.3;
@@ -342,11 +397,109 @@ fn accumulate_token(
             raw.posterization += 0.6;
         }
 
+        Token::Less | Token::GreaterEqual | Token::LessEqual => {
+            raw.contrast += 1.0;
+            raw.edge += 0.3;
+            raw.posterization += 0.6;
+        }
+
+        Token::Equal | Token::NotEqual => {
+            raw.contrast += 0.8;
+            raw.posterization += 0.9;
+            raw.pattern += 0.4 * pattern_mult;
+        }
+
+        Token::Min | Token::Max => {
+            raw.blending += 0.8 * blend_mult;
+            raw.contrast += 0.3;
+            raw.morphological += 0.3;
+        }
+
         Token::Weight => {
             raw.blending += 1.0 * blend_mult;
             raw.blur += 0.6;
         }
 
+        // ── Unary operators ─────────────────────────────────────────────
+
+        Token::Swizzle(_) => {
+            raw.channel += 1.0;
+        }
+
+        Token::Not => {
+            raw.contrast += 0.7;
+            raw.bitwise += 0.5;
+        }
+
+        Token::Neg => {
+            raw.arithmetic += 0.4;
+            raw.contrast += 0.5;
+        }
+
+        Token::Output(_) => {
+            raw.channel += 1.0;
+        }
+
+        // ── Ternary operators ───────────────────────────────────────────
+
+        Token::If => {
+            raw.contrast += 0.8;
+            raw.blending += 0.8 * blend_mult;
+            raw.posterization += 0.3;
+        }
+
+        // ── Function calls ──────────────────────────────────────────────
+
+        Token::Call(Func::Abs, _) => {
+            raw.arithmetic += 0.6;
+            raw.edge += 0.4;
+        }
+
+        Token::Call(Func::Clamp, _) => {
+            raw.contrast += 0.6;
+            raw.posterization += 0.3;
+        }
+
+        Token::Call(Func::Mix, _) => {
+            raw.blending += 1.0 * blend_mult;
+            raw.blur += 0.3;
+        }
+
+        Token::Call(Func::Sin | Func::Cos | Func::Atan | Func::Hypot, _) => {
+            raw.arithmetic += 0.5;
+            raw.pattern += 1.0 * pattern_mult;
+        }
+
+        Token::Call(Func::Sqrt, _) => {
+            raw.arithmetic += 0.5;
+            raw.brightness += 0.6;
+        }
+
+        Token::Call(Func::Avg, _) => {
+            raw.blending += 0.8 * blend_mult;
+            raw.blur += 0.6;
+        }
+
+        Token::Call(Func::Bright, _) => {
+            raw.brightness += 1.0;
+            raw.contrast += 0.3;
+        }
+
+        Token::Call(Func::Hue | Func::Sat | Func::Val, _) => {
+            raw.channel += 0.8;
+        }
+
+        Token::Call(Func::Hsv, _) => {
+            raw.channel += 1.0;
+            raw.brightness += 0.3;
+        }
+
+        Token::Call(Func::Min | Func::Max, _) => {
+            raw.blending += 0.8 * blend_mult;
+            raw.contrast += 0.3;
+            raw.morphological += 0.3;
+        }
+
         // Parens should never be in RPN output, but be safe.
         Token::LeftParen | Token::RightParen => {}
 
@@ -505,4 +658,64 @@ mod tests {
         assert!(c.channel > 0.0, "should have channel");
         assert!(c.displacement > 0.0, "should have displacement");
     }
+
+    #[test]
+    fn test_conditional() {
+        let c = classify("if(c ? 128, h, v)").unwrap();
+        // Contrast: ?=1.0 + if=0.8 = 1.8 raw → ~0.8
+        approx(c.contrast, 0.8, "contrast");
+        // Blending: if=0.8 with 3 sources (c, h, v) → ~0.6
+        approx(c.blending, 0.6, "blending");
+    }
+
+    #[test]
+    fn test_min_max_blending() {
+        let c = classify("h min v").unwrap();
+        approx(c.blending, 0.6, "blending");
+        assert!(c.contrast > 0.0, "contrast: {}", c.contrast);
+    }
+
+    #[test]
+    fn test_function_calls() {
+        let c = classify("sin(x) ^ cos(y)").unwrap();
+        assert!(c.pattern >= 0.9, "pattern: {}", c.pattern);
+
+        let c = classify("mix(h, v, 128)").unwrap();
+        approx(c.blending, 0.6, "blending");
+    }
+
+    #[test]
+    fn test_swizzle_channel() {
+        let c = classify("c.gbr ^ h.r").unwrap();
+        assert!(c.channel >= 0.8, "channel: {}", c.channel);
+    }
+
+    #[test]
+    fn test_blur() {
+        let c = classify("k2").unwrap();
+        approx(c.blur, 0.6, "blur");
+        assert_eq!(c.brightness, 0.0, "blur is not brightness");
+    }
+
+    #[test]
+    fn test_hue_shift() {
+        let c = classify("hsv(hue(c) + 32, sat(c), val(c))").unwrap();
+        assert!(c.channel >= 0.9, "channel: {}", c.channel);
+    }
+
+    #[test]
+    fn test_polar_params() {
+        let c = classify("c ^ (dist + angle)").unwrap();
+        assert!(c.spatial >= 0.8, "spatial: {}", c.spatial);
+        assert!(c.pattern >= 0.8, "pattern: {}", c.pattern);
+    }
+
+    #[test]
+    fn test_previous_frame_feedback() {
+        let c = classify("mix(c, p, 200)").unwrap();
+        assert!(c.feedback >= 0.6, "feedback: {}", c.feedback);
+        let c = classify("p[0, 2]").unwrap();
+        assert!(c.feedback >= 0.6, "feedback: {}", c.feedback);
+        assert!(c.displacement >= 0.6, "displacement: {}", c.displacement);
+    }
 }
@@ -0,0 +1,99 @@
+/// How reads outside of the image are resolved.
+///
+/// Used by every operand that looks at other pixels (`e`, `H`, `L`, `r{N}`,
+/// `t`, `g`, `src[dx, dy]`, ...).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EdgeMode {
+    /// Transparent black outside of the image.
+    #[default]
+    Zero,
+    /// Repeat the nearest edge pixel.
+    Clamp,
+    /// Tile the image, reading from the opposite edge.
+    Wrap,
+    /// Reflect the image at its edges.
+    Mirror,
+}
+
+impl EdgeMode {
+    /// Maps a possibly out-of-bounds position to a pixel inside a `width`x`height`
+    /// image, or `None` if the read should produce transparent black.
+    pub fn resolve(self, x: i64, y: i64, width: u32, height: u32) -> Option<(u32, u32)> {
+        Some((self.resolve_axis(x, width)?, self.resolve_axis(y, height)?))
+    }
+
+    fn resolve_axis(self, v: i64, len: u32) -> Option<u32> {
+        let len = i64::from(len);
+        if len == 0 {
+            return None;
+        }
+        if (0..len).contains(&v) {
+            return Some(v as u32);
+        }
+
+        let v = match self {
+            Self::Zero => return None,
+            Self::Clamp => v.clamp(0, len - 1),
+            Self::Wrap => v.rem_euclid(len),
+            Self::Mirror => {
+                let m = v.rem_euclid(2 * len);
+                if m < len {
+                    m
+                } else {
+                    2 * len - 1 - m
+                }
+            }
+        };
+        Some(v as u32)
+    }
+}
+
+impl std::str::FromStr for EdgeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "zero" => Ok(Self::Zero),
+            "clamp" => Ok(Self::Clamp),
+            "wrap" => Ok(Self::Wrap),
+            "mirror" => Ok(Self::Mirror),
+            _ => Err(format!(
+                "Unknown edge mode '{}' (expected zero, clamp, wrap or mirror)",
+                s
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_in_bounds_is_unchanged() {
+        for mode in [
+            EdgeMode::Zero,
+            EdgeMode::Clamp,
+            EdgeMode::Wrap,
+            EdgeMode::Mirror,
+        ] {
+            assert_eq!(mode.resolve(2, 3, 4, 4), Some((2, 3)));
+        }
+    }
+
+    #[test]
+    fn test_out_of_bounds() {
+        assert_eq!(EdgeMode::Zero.resolve(-1, 0, 4, 4), None);
+        assert_eq!(EdgeMode::Clamp.resolve(-3, 9, 4, 4), Some((0, 3)));
+        assert_eq!(EdgeMode::Wrap.resolve(-1, 5, 4, 4), Some((3, 1)));
+        assert_eq!(EdgeMode::Mirror.resolve(-1, 4, 4, 4), Some((0, 3)));
+        assert_eq!(EdgeMode::Mirror.resolve(-5, 9, 4, 4), Some((3, 1)));
+    }
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!("wrap".parse(), Ok(EdgeMode::Wrap));
+        assert_eq!("Mirror".parse(), Ok(EdgeMode::Mirror));
+        assert!("repeat".parse::<EdgeMode>().is_err());
+    }
+}
@@ -0,0 +1,192 @@
+use std::fmt;
+use std::ops::Range;
+
+/// Byte range into the expression source.
+pub type Span = Range<usize>;
+
+/// Everything that can go wrong parsing, verifying or evaluating an expression.
+///
+/// Errors found in the source carry the [`Span`] they were found at, so they can
+/// be shown under the expression with [`GlitchError::render`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GlitchError {
+    /// A character that isn't part of the expression language.
+    InvalidChar { ch: char, span: Span },
+    /// A literal or operand parameter (`256`, `r0`, `k300`) outside of `min..=255`.
+    NumberOutOfRange { min: u8, span: Span },
+    /// An opening bracket that is never closed, or a closing one that doesn't match.
+    MismatchedParen { span: Span },
+    /// Parentheses with nothing inside, e.g. `c & ()`.
+    EmptyParens { span: Span },
+    /// An operator, `,` or `)` where a value should be, e.g. the `*` in `c + * h`.
+    MissingOperand { span: Span },
+    /// A value directly after another value, e.g. the `h` in `c h`.
+    MissingOperator { span: Span },
+    /// An operator, call or statement without enough operands.
+    StackUnderflow {
+        token: String,
+        needs: usize,
+        found: usize,
+        span: Span,
+    },
+    /// A statement that leaves more than one value, i.e. is missing operators.
+    ExtraValues { count: usize, span: Span },
+    /// A statement that produces no value at all.
+    NoResult { span: Span },
+    /// A function or `if` called with the wrong number of arguments.
+    Arity {
+        name: String,
+        expected: String,
+        found: usize,
+        span: Span,
+    },
+    /// Any other syntax error, such as an unknown identifier or a misplaced `use`.
+    Syntax { message: String, span: Span },
+    /// An error inside a binding or per-channel statement, labelled with the statement
+    /// (e.g. `Channel 'g'`).
+    Statement { label: String, error: Box<Self> },
+    /// A failure while evaluating tokens that weren't verified.
+    Eval { message: String },
+}
+
+impl GlitchError {
+    pub(crate) fn syntax(message: impl Into<String>, span: Span) -> Self {
+        Self::Syntax {
+            message: message.into(),
+            span,
+        }
+    }
+
+    pub(crate) fn eval(message: impl Into<String>) -> Self {
+        Self::Eval {
+            message: message.into(),
+        }
+    }
+
+    /// Where in the source the error was found, if it came from the source.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Self::InvalidChar { span, .. }
+            | Self::NumberOutOfRange { span, .. }
+            | Self::MismatchedParen { span }
+            | Self::EmptyParens { span }
+            | Self::MissingOperand { span }
+            | Self::MissingOperator { span }
+            | Self::StackUnderflow { span, .. }
+            | Self::ExtraValues { span, .. }
+            | Self::NoResult { span }
+            | Self::Arity { span, .. }
+            | Self::Syntax { span, .. } => Some(span.clone()),
+            Self::Statement { error, .. } => error.span(),
+            Self::Eval { .. } => None,
+        }
+    }
+
+    /// Renders the error with `source` underneath and a caret under the span:
+    ///
+    /// ```text
+    /// Number must be between 0 and 255 at position 7
+    ///   128 & 300
+    ///         ^^^
+    /// ```
+    ///
+    /// `source` must be the expression the error came from.
+    pub fn render(&self, source: &str) -> String {
+        let Some(span) = self.span() else {
+            return self.to_string();
+        };
+        let start = span.start.min(source.len());
+        let end = span.end.clamp(start, source.len());
+
+        // Keep tabs so the caret lines up with the source
+        let indent: String = source[..start]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let width = source[start..end].chars().count().max(1);
+
+        format!("{}\n  {}\n  {}{}", self, source, indent, "^".repeat(width))
+    }
+}
+
+impl fmt::Display for GlitchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidChar { ch, .. } => write!(f, "Invalid character '{}'", ch)?,
+            Self::NumberOutOfRange { min, .. } => {
+                write!(f, "Number must be between {} and 255", min)?
+            }
+            Self::MismatchedParen { .. } => f.write_str("Mismatched parenthesis")?,
+            Self::EmptyParens { .. } => f.write_str("Empty parentheses")?,
+            Self::MissingOperand { .. } => f.write_str("Expected a value")?,
+            Self::MissingOperator { .. } => f.write_str("Missing operator between values")?,
+            Self::StackUnderflow {
+                token,
+                needs,
+                found,
+                ..
+            } => write!(
+                f,
+                "Stack underflow: {} needs {} operand{} but stack has {}",
+                token,
+                needs,
+                if *needs == 1 { "" } else { "s" },
+                found
+            )?,
+            Self::ExtraValues { count, .. } => write!(
+                f,
+                "Produces {} values instead of 1 — missing operator(s) between values",
+                count
+            )?,
+            Self::NoResult { .. } => f.write_str("Produces no result")?,
+            Self::Arity {
+                name,
+                expected,
+                found,
+                ..
+            } => write!(f, "'{}' expects {} but got {}", name, expected, found)?,
+            Self::Syntax { message, .. } => f.write_str(message)?,
+            Self::Statement { label, error } => return write!(f, "{}: {}", label, error),
+            Self::Eval { message } => return f.write_str(message),
+        }
+        self.span()
+            .map_or(Ok(()), |span| write!(f, " at position {}", span.start + 1))
+    }
+}
+
+impl std::error::Error for GlitchError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_caret() {
+        let err = GlitchError::NumberOutOfRange { min: 0, span: 6..9 };
+        assert_eq!(
+            err.render("128 & 300"),
+            "Number must be between 0 and 255 at position 7\n  128 & 300\n        ^^^"
+        );
+
+        // Errors at the end of the source point just past it
+        let err = GlitchError::MismatchedParen { span: 3..3 };
+        assert_eq!(
+            err.render("(c "),
+            "Mismatched parenthesis at position 4\n  (c \n     ^"
+        );
+    }
+
+    #[test]
+    fn test_statement_label() {
+        let err = GlitchError::Statement {
+            label: "Channel 'g'".to_string(),
+            error: Box::new(GlitchError::NoResult { span: 3..4 }),
+        };
+        assert_eq!(
+            err.to_string(),
+            "Channel 'g': Produces no result at position 4"
+        );
+        assert_eq!(err.span(), Some(3..4));
+        assert_eq!(GlitchError::eval("Stack underflow").span(), None);
+    }
+}
@@ -1,506 +1,562 @@
+use crate::blur::BlurTable;
+use crate::edge::EdgeMode;
+use crate::error::GlitchError;
+use crate::numeric::{Lane, NumericMode};
+use crate::program::{Buffered, Feature, Op, Program, Scratch};
 use crate::rgb::Rgb;
-use crate::token::Token;
+use crate::space::ColorSpace;
+use crate::token::{Func, Param, Token};
 use image::{DynamicImage, GenericImageView, Pixel, Rgba};
 use rand::{Rng, RngCore};
-use std::collections::HashMap;
-use std::ops::{BitAnd, BitOr, BitXor};
-
-#[derive(Debug, Default)]
-struct SumSave {
-    v_y: Option<Rgb>,
-    v_b: Option<Rgb>,
-    v_e: Option<Rgb>,
-    v_r: Option<HashMap<u8, Rgb>>,
-    v_t: Option<Rgb>,
-    v_g: Option<Rgb>,
-    v_h: Option<Rgb>,
-    v_v: Option<Rgb>,
-    v_d: Option<Rgb>,
-    v_high: Option<Rgb>,
-    v_low: Option<Rgb>,
-}
+use std::f64::consts::TAU;
 
 #[derive(Debug, Clone)]
 pub struct EvalContext<'a> {
-    pub tokens: &'a [Token],
     pub size: (u32, u32),
     pub rgba: Rgba<u8>,
+    /// Output of the previously evaluated pixel, read by `s`.
     pub saved_rgb: [u8; 3],
     pub position: (u32, u32),
 
     pub ignore_state: bool,
+    /// Return transparent black for fully transparent pixels without evaluating.
+    pub skip_transparent: bool,
+    /// How reads outside of the image are resolved, for every operand that samples neighbours.
+    pub edge: EdgeMode,
+    /// Colour space the expression runs in, unless it picks one with `use <space>;`.
+    pub space: ColorSpace,
+    /// How intermediate values are carried, unless the expression picks a mode with `use <mode>;`.
+    pub numeric: NumericMode,
+    /// Index of the frame being processed and the number of frames, `(0, 1)` for still images.
+    pub frame: (u32, u32),
+    /// Output of the previous animation frame, read by `p`. Falls back to the input image.
+    pub previous: Option<&'a DynamicImage>,
+    /// Box sums of the input for `k<n>`, from [`Program::blur_table`]. Without
+    /// them, every pixel of the box is read.
+    pub blur: Option<&'a BlurTable>,
 }
 
-fn binary_stack_op(stack: &mut Vec<Rgb>, op: fn(u8, u8) -> u8) -> Result<(), String> {
+impl EvalContext<'_> {
+    /// Context for the pixel at `position` of a still image, with every option
+    /// at its default.
+    pub fn at(image: &DynamicImage, (x, y): (u32, u32)) -> Self {
+        Self {
+            size: image.dimensions(),
+            rgba: image.get_pixel(x, y),
+            saved_rgb: [0, 0, 0],
+            position: (x, y),
+            ignore_state: false,
+            skip_transparent: false,
+            edge: EdgeMode::default(),
+            space: ColorSpace::default(),
+            numeric: NumericMode::default(),
+            frame: (0, 1),
+            previous: None,
+            blur: None,
+        }
+    }
+}
+
+/// A value on the evaluation stack, one lane per color component.
+type Value<L> = [L; 3];
+
+fn lift<L: Lane>(rgb: Rgb) -> Value<L> {
+    [
+        L::from_byte(rgb.r),
+        L::from_byte(rgb.g),
+        L::from_byte(rgb.b),
+    ]
+}
+
+fn binary_stack_op<L: Lane>(stack: &mut Vec<Value<L>>, op: fn(L, L) -> L) -> Result<(), String> {
     let b = stack.pop().ok_or("Stack underflow")?;
     let a = stack.pop().ok_or("Stack underflow")?;
-    stack.push(Rgb::new(op(a.r, b.r), op(a.g, b.g), op(a.b, b.b)));
+    stack.push([op(a[0], b[0]), op(a[1], b[1]), op(a[2], b[2])]);
     Ok(())
 }
 
-enum ChannelOp {
-    Pow,
-    BitLShift,
-    BitRShift,
+fn unary_stack_op<L: Lane>(stack: &mut Vec<Value<L>>, op: fn(L) -> L) -> Result<(), String> {
+    let v = stack.pop().ok_or("Stack underflow")?;
+    stack.push(v.map(op));
+    Ok(())
 }
 
-fn channel_op(stack: &mut Vec<Rgb>, op: ChannelOp) -> Result<(), String> {
-    let b = stack.pop().ok_or("Stack underflow")?;
-    let a = stack.pop().ok_or("Stack underflow")?;
+fn compare<L: Lane>(holds: bool) -> L {
+    if holds {
+        L::TRUE
+    } else {
+        L::default()
+    }
+}
 
-    let (rr, gg, bb) = match op {
-        ChannelOp::Pow => (
-            a.r.wrapping_pow(b.r.into()),
-            a.g.wrapping_pow(b.g.into()),
-            a.b.wrapping_pow(b.b.into()),
-        ),
-        ChannelOp::BitLShift => (
-            a.r.wrapping_shl(b.r.into()),
-            a.g.wrapping_shl(b.g.into()),
-            a.b.wrapping_shl(b.b.into()),
-        ),
-        ChannelOp::BitRShift => (
-            a.r.wrapping_shr(b.r.into()),
-            a.g.wrapping_shr(b.g.into()),
-            a.b.wrapping_shr(b.b.into()),
-        ),
+/// The lane operation of a binary operator.
+pub(crate) fn binary_op<L: Lane>(tok: &Token) -> Option<fn(L, L) -> L> {
+    let op: fn(L, L) -> L = match tok {
+        Token::Add => L::add,
+        Token::Sub => L::sub,
+        Token::Mul => L::mul,
+        Token::Div => L::div,
+        Token::Mod => L::rem,
+        Token::BitAnd => L::bitand,
+        Token::BitOr => L::bitor,
+        Token::BitXor => L::bitxor,
+        Token::BitAndNot => L::and_not,
+        Token::Weight => L::weight,
+        Token::Greater => |a, b| compare(a > b),
+        Token::Less => |a, b| compare(a < b),
+        Token::GreaterEqual => |a, b| compare(a >= b),
+        Token::LessEqual => |a, b| compare(a <= b),
+        Token::Equal => |a, b| compare(a == b),
+        Token::NotEqual => |a, b| compare(a != b),
+        Token::Min => |a, b| if b < a { b } else { a },
+        Token::Max => |a, b| if b > a { b } else { a },
+        Token::Pow => L::pow,
+        Token::BitLShift => L::shl,
+        Token::BitRShift => L::shr,
+        _ => return None,
     };
+    Some(op)
+}
 
-    stack.push(Rgb::new(rr, gg, bb));
-    Ok(())
+/// The lane operation of a prefix operator.
+pub(crate) fn unary_op<L: Lane>(tok: &Token) -> Option<fn(L) -> L> {
+    match tok {
+        Token::Not => Some(L::not),
+        Token::Neg => Some(L::neg),
+        _ => None,
+    }
 }
 
+/// Applies a built-in function channel-wise, treating `0..=255` as the unit range.
+pub(crate) fn call_func<L: Lane>(func: Func, args: &[Value<L>]) -> Value<L> {
+    let wave = |v: L, f: fn(f64) -> f64| L::from_unit((f(v.to_unit() * TAU) + 1.0) / 2.0);
+    // Color functions work on the written bytes
+    let rgb = |n: usize| args[n].map(L::saturate);
+
+    let mut out = [L::default(); 3];
+    for (i, o) in out.iter_mut().enumerate() {
+        let channel = |value: &Value<L>| value[i];
+        let arg = |n: usize| channel(&args[n]);
+        let pick = |a: L, b: L, take_b: bool| if take_b { b } else { a };
+
+        *o = match func {
+            Func::Abs => arg(0).abs(),
+            Func::Clamp => {
+                let v = pick(arg(0), arg(1), arg(1) > arg(0));
+                pick(v, arg(2), arg(2) < v)
+            }
+            Func::Mix => arg(0).mix(arg(1), arg(2)),
+            Func::Sin => wave(arg(0), f64::sin),
+            Func::Cos => wave(arg(0), f64::cos),
+            Func::Sqrt => L::from_unit(arg(0).to_unit().sqrt()),
+            Func::Avg => L::mean(args.iter().map(channel)),
+            Func::Min => args
+                .iter()
+                .map(channel)
+                .fold(arg(0), |m, v| pick(m, v, v < m)),
+            Func::Max => args
+                .iter()
+                .map(channel)
+                .fold(arg(0), |m, v| pick(m, v, v > m)),
+            // Brightness needs the whole color; the factor is the average of `n`
+            Func::Bright => {
+                let [r, g, b] = rgb(0);
+                let factor = rgb(1).iter().map(|&v| f64::from(v)).sum::<f64>() / (3.0 * 255.0);
+                L::from_byte(<[u8; 3]>::from(adjust_brightness_hsv(r, g, b, factor))[i])
+            }
+            // Hue wraps around the color wheel over the whole byte, so `hue(c) + 128`
+            // is the complementary hue
+            Func::Hue => {
+                let [r, g, b] = rgb(0);
+                L::from_turn(rgb_to_hsv(r, g, b).0)
+            }
+            Func::Sat => {
+                let [r, g, b] = rgb(0);
+                L::from_unit(rgb_to_hsv(r, g, b).1)
+            }
+            Func::Val => {
+                let [r, g, b] = rgb(0);
+                L::from_unit(rgb_to_hsv(r, g, b).2)
+            }
+            Func::Hsv => {
+                let unit = |n: usize| arg(n).to_unit().clamp(0.0, 1.0);
+                let rgb = hsv_to_rgb(arg(0).to_turn().rem_euclid(1.0), unit(1), unit(2));
+                L::from_byte(<[u8; 3]>::from(rgb)[i])
+            }
+            Func::Atan => {
+                let turn = arg(0).to_signed_unit().atan2(arg(1).to_signed_unit()) / TAU;
+                L::from_turn(turn)
+            }
+            Func::Hypot => L::from_unit(arg(0).to_signed_unit().hypot(arg(1).to_signed_unit())),
+        };
+    }
+
+    out
+}
+
+/// Evaluates the tokens for a single pixel.
+///
+/// Compiles the tokens first, so rendering many pixels should compile a
+/// [`Program`] once and evaluate that instead.
+///
+/// # Errors
+/// Returns [`GlitchError::Eval`] if the tokens are malformed, which [`crate::verify`]
+/// rules out.
 pub fn eval<R: RngCore>(
+    tokens: &[Token],
+    ctx: EvalContext<'_>,
+    input: &DynamicImage,
+    rng: &mut R,
+) -> Result<Rgba<u8>, GlitchError> {
+    Program::compile(tokens)?.eval(ctx, input, rng, &mut Scratch::default())
+}
+
+pub(crate) fn run<L: Buffered, R: RngCore>(
+    program: &Program,
     ctx: EvalContext<'_>,
     input: &DynamicImage,
     rng: &mut R,
+    scratch: &mut Scratch,
 ) -> Result<Rgba<u8>, String> {
     let EvalContext {
-        tokens,
         size,
         rgba,
         saved_rgb,
         position,
         ignore_state,
+        skip_transparent,
+        edge,
+        space,
+        numeric,
+        frame,
+        previous,
+        blur,
     } = ctx;
+    let space = program.space.unwrap_or(space);
+    let numeric = program.numeric.unwrap_or(numeric);
     let (width, height) = size;
     let (x, y) = position;
 
-    let r = rgba[0];
-    let g = rgba[1];
-    let b = rgba[2];
     let a = rgba[3];
-
-    let [sr, sg, sb] = saved_rgb;
-
-    if a == 0 {
+    if skip_transparent && a == 0 {
         return Ok(Rgba([0, 0, 0, 0]));
     }
 
-    let mut stack: Vec<Rgb> = Vec::with_capacity(tokens.len());
-
-    let div = |a: u8, b: u8| -> u8 {
-        if b == 0 {
-            return a;
-        }
-        a.wrapping_div(b)
-    };
-
-    let modu = |a: u8, b: u8| -> u8 {
-        if b == 0 {
-            return a;
-        }
-        a.wrapping_rem(b)
-    };
-
-    let bit_and_not = |a: u8, b: u8| -> u8 { a & !b };
+    let [r, g, b] = space.from_rgb([rgba[0], rgba[1], rgba[2]]);
+    let [sr, sg, sb] = space.from_rgb(saved_rgb);
 
-    let weight = |a: u8, b: u8| -> u8 {
-        let fuzz = f64::from(b) / 255.0;
-        let r = f64::from(a) * fuzz;
-        r as u8
+    // Reads outside of the image follow the edge mode.
+    let read_pixel = |image: &DynamicImage, x: i64, y: i64| -> [u8; 4] {
+        let [pr, pg, pb, pa] = edge
+            .resolve(x, y, image.width(), image.height())
+            .map_or([0, 0, 0, 0], |(px, py)| image.get_pixel(px, py).0);
+        let [pr, pg, pb] = space.from_rgb([pr, pg, pb]);
+        [pr, pg, pb, pa]
     };
-
-    let three_rule = |x: u32, max: u32| -> u8 { (((255 * x) / max) & 255) as u8 };
-
-    let is_in_bounds = |x: u32, y: u32| -> bool { x < width && y < height };
-
-    let get_pixel_in_bounds = |x: u32, y: u32| -> [u8; 4] {
-        if is_in_bounds(x, y) {
-            input.get_pixel(x, y).0
-        } else {
-            [0, 0, 0, 0]
-        }
+    let get_pixel_in_bounds = |x: i64, y: i64| read_pixel(input, x, y);
+    let get_previous_pixel = |x: i64, y: i64| read_pixel(previous.unwrap_or(input), x, y);
+
+    // Reads `source` as if it were evaluated at (sx, sy).
+    let sample = |source: char, sx: i64, sy: i64| -> [u8; 4] {
+        let (w, h) = (i64::from(width), i64::from(height));
+        let (sx, sy) = match source {
+            'p' => return get_previous_pixel(sx, sy),
+            'h' => (w - 1 - sx, sy),
+            'v' => (sx, h - 1 - sy),
+            'd' => (w - 1 - sx, h - 1 - sy),
+            _ => (sx, sy),
+        };
+        get_pixel_in_bounds(sx, sy)
     };
 
     let rgb_from_colors = |colors: &[(i32, i32); 3]| -> Rgb {
         let mut rgb = [0; 3];
         for (i, (xx, yy)) in colors.iter().enumerate() {
-            let x = (xx + x as i32) as u32;
-            let y = (yy + y as i32) as u32;
+            let x = i64::from(*xx) + i64::from(x);
+            let y = i64::from(*yy) + i64::from(y);
             let pixel = get_pixel_in_bounds(x, y);
             rgb[i] = pixel[i];
         }
         Rgb::from(rgb)
     };
 
-    let mut saved = SumSave::default();
-
-    for tok in tokens.iter().cloned() {
-        match tok {
-            Token::Num(n) => stack.push(Rgb::new(n, n, n)),
-
-            Token::Add => binary_stack_op(&mut stack, u8::wrapping_add)?,
-            Token::Sub => binary_stack_op(&mut stack, u8::wrapping_sub)?,
-            Token::Mul => binary_stack_op(&mut stack, u8::wrapping_mul)?,
-            Token::Div => binary_stack_op(&mut stack, div)?,
-            Token::Mod => binary_stack_op(&mut stack, modu)?,
-            Token::BitAnd => binary_stack_op(&mut stack, u8::bitand)?,
-            Token::BitOr => binary_stack_op(&mut stack, u8::bitor)?,
-            Token::BitXor => binary_stack_op(&mut stack, u8::bitxor)?,
-            Token::BitAndNot => binary_stack_op(&mut stack, bit_and_not)?,
-            Token::Weight => binary_stack_op(&mut stack, weight)?,
-            Token::Pow => channel_op(&mut stack, ChannelOp::Pow)?,
-            Token::BitLShift => channel_op(&mut stack, ChannelOp::BitLShift)?,
-            Token::BitRShift => channel_op(&mut stack, ChannelOp::BitRShift)?,
-
-            Token::Greater => {
-                let b = stack.pop().ok_or("Stack underflow")?;
-                let a = stack.pop().ok_or("Stack underflow")?;
-
-                stack.push(Rgb::new(
-                    if a.r > b.r { 255 } else { 0 },
-                    if a.g > b.g { 255 } else { 0 },
-                    if a.b > b.b { 255 } else { 0 },
-                ));
+    // Reads of the 3x3 neighbourhood, with the pixel itself in the middle
+    let boxed = || {
+        fetch_boxed(
+            i64::from(x),
+            i64::from(y),
+            Rgb::new(r, g, b),
+            get_pixel_in_bounds,
+        )
+    };
+    let neighbours = |boxed: &[Rgb; 9], channel: fn(&Rgb) -> u8| {
+        [0, 1, 2, 3, 5, 6, 7, 8].map(|i| channel(&boxed[i]))
+    };
+
+    let compute = |feature: Feature, rng: &mut R| -> Rgb {
+        match feature {
+            Feature::Luma => {
+                let y = luminance(r, g, b);
+                Rgb::new(y, y, y)
             }
+            Feature::Horizontal => {
+                let h = width.wrapping_sub(x).wrapping_sub(1);
+                let pixel = get_pixel_in_bounds(i64::from(h), i64::from(y));
+                Rgb::new(pixel[0], pixel[1], pixel[2])
+            }
+            Feature::Vertical => {
+                let v = height.wrapping_sub(y).wrapping_sub(1);
+                let pixel = get_pixel_in_bounds(i64::from(x), i64::from(v));
+                Rgb::new(pixel[0], pixel[1], pixel[2])
+            }
+            Feature::Diagonal => {
+                let x = width.wrapping_sub(x).wrapping_sub(1);
+                let y = height.wrapping_sub(y).wrapping_sub(1);
+                let pixel = get_pixel_in_bounds(i64::from(x), i64::from(y));
+                Rgb::new(pixel[0], pixel[1], pixel[2])
+            }
+            Feature::Edge => {
+                let boxed = boxed();
+                let edge = |channel: fn(&Rgb) -> u8| {
+                    let [b0, b1, b2, b3, _, b5, b6, b7, b8] = boxed.map(|rgb| channel(&rgb));
+                    b8.wrapping_sub(b0)
+                        .wrapping_add(b5)
+                        .wrapping_sub(b3)
+                        .wrapping_add(b7)
+                        .wrapping_sub(b1)
+                        .wrapping_add(b6)
+                        .wrapping_sub(b2)
+                };
+                Rgb::new(edge(|p| p.r), edge(|p| p.g), edge(|p| p.b))
+            }
+            Feature::Highest => {
+                let boxed = boxed();
+                Rgb::new(
+                    max(neighbours(&boxed, |p| p.r)),
+                    max(neighbours(&boxed, |p| p.g)),
+                    max(neighbours(&boxed, |p| p.b)),
+                )
+            }
+            Feature::Lowest => {
+                let boxed = boxed();
+                Rgb::new(
+                    min(neighbours(&boxed, |p| p.r)),
+                    min(neighbours(&boxed, |p| p.g)),
+                    min(neighbours(&boxed, |p| p.b)),
+                )
+            }
+            Feature::Blur(radius) => blur
+                .and_then(|table| table.mean((x, y), radius))
+                .unwrap_or_else(|| {
+                    let radius = i64::from(radius);
+                    let (cx, cy) = (i64::from(x), i64::from(y));
+                    let mut sum = [0u32; 3];
+                    for j in cy - radius..=cy + radius {
+                        for i in cx - radius..=cx + radius {
+                            let pixel = get_pixel_in_bounds(i, j);
+                            for (s, p) in sum.iter_mut().zip(pixel) {
+                                *s += u32::from(p);
+                            }
+                        }
+                    }
 
-            Token::Random(num) => {
+                    let count = ((2 * radius + 1) * (2 * radius + 1)) as u32;
+                    Rgb::from(sum.map(|s| (s / count) as u8))
+                }),
+            Feature::Nearby => rgb_from_colors(&gen_random_position(-2, 2, rng)),
+            Feature::Anywhere => rgb_from_colors(&gen_random_position(0i32, width as i32, rng)),
+            Feature::Random(num) => {
                 let neg = std::ops::Neg::neg(num as i8);
+                rgb_from_colors(&gen_random_position(neg as i32, num as i32, rng))
+            }
+        }
+    };
 
-                let v_r = saved.v_r.as_ref().and_then(|v| v.get(&num));
-
-                let v_r = if let Some(v) = v_r {
-                    *v
-                } else {
-                    let colors = gen_random_position(neg as i32, num as i32, rng);
-                    let rgb = rgb_from_colors(&colors);
-                    if !ignore_state {
-                        saved.v_r.get_or_insert_with(HashMap::new).insert(num, rgb);
+    let (buffers, features) = L::buffers(scratch, program);
+    let stack = &mut buffers.stack;
+    // Per-pixel binding slots, filled by `Op::Store`.
+    let slots = &mut buffers.slots;
+    // Channels set by per-channel statements, filled by `Op::Output`.
+    let mut outputs: [Option<L>; 4] = [None; 4];
+
+    for op in &program.ops {
+        match *op {
+            Op::Num(n) => stack.push([L::from_literal(n); 3]),
+            Op::Float(v) => stack.push([L::from_decimal(v); 3]),
+
+            Op::Binary(op) => binary_stack_op(stack, L::binary(op))?,
+            Op::Unary(op) => unary_stack_op(stack, L::unary(op))?,
+
+            Op::Feature(index) => {
+                let feature = program.features()[index];
+                let value = features[index].unwrap_or_else(|| {
+                    let value = compute(feature, rng);
+                    // Without state, random features are drawn again for every use
+                    if !(ignore_state && feature.draws_random()) {
+                        features[index] = Some(value);
                     }
-                    rgb
-                };
-
-                stack.push(v_r);
+                    value
+                });
+                stack.push(lift(value));
             }
 
-            Token::RGBColor((token, num)) => match token {
-                'R' => stack.push(Rgb::new_red(num)),
-                'G' => stack.push(Rgb::new_green(num)),
-                'B' => stack.push(Rgb::new_blue(num)),
-                _ => return Err(format!("Unexpected token: {:?}", token)),
-            },
-
-            Token::Brightness(brightness_value) => {
-                let factor = (brightness_value as f64 / 255.0).clamp(0.0, 1.0);
-
-                let pixel = get_pixel_in_bounds(x, y);
-                let (nr, ng, nb) = adjust_brightness_hsv(pixel[0], pixel[1], pixel[2], factor);
-
-                stack.push(Rgb::new(nr, ng, nb));
+            Op::Sample(source) => {
+                let dy = stack.pop().ok_or("Stack underflow")?;
+                let dx = stack.pop().ok_or("Stack underflow")?;
+
+                // Offsets are signed and per channel, so `c[R4 > 0, 0]` only shifts red
+                let mut value = [0u8; 3];
+                for (i, v) in value.iter_mut().enumerate() {
+                    let sx = i64::from(x) + dx[i].offset();
+                    let sy = i64::from(y) + dy[i].offset();
+                    let [pr, pg, pb, pa] = sample(source, sx, sy);
+                    *v = match source {
+                        'Y' => luminance(pr, pg, pb),
+                        'A' => pa,
+                        _ => [pr, pg, pb][i],
+                    };
+                }
+                stack.push(lift(Rgb::from(value)));
             }
 
-            Token::Invert => {
-                let pixel = get_pixel_in_bounds(x, y);
-                let mut new_rgba = Rgba([pixel[0], pixel[1], pixel[2], pixel[3]]);
-                new_rgba.invert();
-
-                stack.push(Rgb::new(new_rgba[0], new_rgba[1], new_rgba[2]));
+            Op::Swizzle([i, j, k]) => {
+                let value = stack.pop().ok_or("Stack underflow")?;
+                stack.push([value[i], value[j], value[k]]);
             }
 
-            Token::Char(c) => match c {
-                'c' => stack.push(Rgb::new(r, g, b)),
-                'Y' => {
-                    let v_y = match saved.v_y {
-                        Some(v_y) => v_y,
-                        None => {
-                            let y = f64::from(b)
-                                .mul_add(0.0722, f64::from(r).mul_add(0.299, f64::from(g) * 0.587));
-                            let v_y = Rgb::new(y as u8, y as u8, y as u8);
-                            saved.v_y = Some(v_y);
-                            v_y
-                        }
-                    };
-
-                    stack.push(v_y);
-                }
-                's' => stack.push(Rgb::new(sr, sg, sb)),
-                'x' => {
-                    let xu = three_rule(x, width);
-                    stack.push(Rgb::new(xu, xu, xu));
-                }
-                'y' => {
-                    let yu = three_rule(y, height);
-                    stack.push(Rgb::new(yu, yu, yu));
-                }
+            Op::If => {
+                let otherwise = stack.pop().ok_or("Stack underflow")?;
+                let then = stack.pop().ok_or("Stack underflow")?;
+                let cond = stack.pop().ok_or("Stack underflow")?;
 
-                't' => {
-                    let v_t = match saved.v_t {
-                        Some(v_t) => v_t,
-                        None => {
-                            let colors = gen_random_position(-2, 2, rng);
+                stack.push([0, 1, 2].map(|i| {
+                    if cond[i].is_true() {
+                        then[i]
+                    } else {
+                        otherwise[i]
+                    }
+                }));
+            }
 
-                            let rgb = rgb_from_colors(&colors);
-                            if !ignore_state {
-                                saved.v_t = Some(rgb);
-                            }
-                            rgb
-                        }
-                    };
+            Op::Call(func, args) => {
+                let at = stack.len().checked_sub(args).ok_or("Stack underflow")?;
+                let value = call_func(func, &stack[at..]);
+                stack.truncate(at);
+                stack.push(value);
+            }
 
-                    stack.push(v_t);
-                }
-                'g' => {
-                    let v_g = match saved.v_g {
-                        Some(v_g) => v_g,
-                        None => {
-                            let colors = gen_random_position(0i32, width as i32, rng);
-
-                            let rgb = rgb_from_colors(&colors);
-                            if !ignore_state {
-                                saved.v_g = Some(rgb);
-                            }
-                            rgb
-                        }
-                    };
+            Op::Store(slot) => slots[slot] = stack.pop().ok_or("Stack underflow")?,
+            Op::Load(slot) => stack.push(slots[slot]),
 
-                    stack.push(v_g);
-                }
-                'e' => {
-                    let v_e = match saved.v_e {
-                        Some(v_e) => v_e,
-                        None => {
-                            let boxed = fetch_boxed(input, x as i32, y as i32, r, g, b);
-
-                            let rr = boxed[8]
-                                .r
-                                .wrapping_sub(boxed[0].r)
-                                .wrapping_add(boxed[5].r)
-                                .wrapping_sub(boxed[3].r)
-                                .wrapping_add(boxed[7].r)
-                                .wrapping_sub(boxed[1].r)
-                                .wrapping_add(boxed[6].r)
-                                .wrapping_sub(boxed[2].r);
-
-                            let gg = boxed[8]
-                                .g
-                                .wrapping_sub(boxed[0].g)
-                                .wrapping_add(boxed[5].g)
-                                .wrapping_sub(boxed[3].g)
-                                .wrapping_add(boxed[7].g)
-                                .wrapping_sub(boxed[1].g)
-                                .wrapping_add(boxed[6].g)
-                                .wrapping_sub(boxed[2].g);
-
-                            let bb = boxed[8]
-                                .b
-                                .wrapping_sub(boxed[0].b)
-                                .wrapping_add(boxed[5].b)
-                                .wrapping_sub(boxed[3].b)
-                                .wrapping_add(boxed[7].b)
-                                .wrapping_sub(boxed[1].b)
-                                .wrapping_add(boxed[6].b)
-                                .wrapping_sub(boxed[2].b);
-
-                            let v_e = Rgb::new(rr, gg, bb);
-                            saved.v_e = Some(v_e);
-                            v_e
-                        }
-                    };
+            Op::Output(channel) => {
+                let value = stack.pop().ok_or("Stack underflow")?;
+                outputs[channel] = Some(match channel {
+                    // Alpha has no component of its own, so it takes the average
+                    3 => L::mean(value),
+                    _ => value[channel],
+                });
+            }
 
-                    stack.push(v_e);
-                }
-                'b' => {
-                    let v_b = match saved.v_b {
-                        Some(v_b) => v_b,
-                        None => {
-                            let boxed = fetch_boxed(input, x as i32, y as i32, r, g, b);
-
-                            let rr = wrapping_vec_add_u32([
-                                boxed[0].r, boxed[1].r, boxed[2].r, boxed[3].r, boxed[5].r,
-                                boxed[6].r, boxed[7].r, boxed[8].r,
-                            ]);
-                            let gg = wrapping_vec_add_u32([
-                                boxed[0].g, boxed[1].g, boxed[2].g, boxed[3].g, boxed[5].g,
-                                boxed[6].g, boxed[7].g, boxed[8].g,
-                            ]);
-                            let bb = wrapping_vec_add_u32([
-                                boxed[0].b, boxed[1].b, boxed[2].b, boxed[3].b, boxed[5].b,
-                                boxed[6].b, boxed[7].b, boxed[8].b,
-                            ]);
-
-                            let v_b = Rgb::new((rr / 9) as u8, (gg / 9) as u8, (bb / 9) as u8);
-                            if !ignore_state {
-                                saved.v_b = Some(v_b);
-                            }
-                            v_b
-                        }
-                    };
+            Op::Color(channel, value) => {
+                let mut rgb = [0; 3];
+                rgb[channel] = value;
+                stack.push(lift(Rgb::from(rgb)));
+            }
 
-                    stack.push(v_b);
-                }
-                'H' => {
-                    let v_h = match saved.v_high {
-                        Some(v_h) => v_h,
-                        None => {
-                            let boxed = fetch_boxed(input, x as i32, y as i32, r, g, b);
-
-                            let r_m = max([
-                                boxed[0].r, boxed[1].r, boxed[2].r, boxed[3].r, boxed[5].r,
-                                boxed[6].r, boxed[7].r, boxed[8].r,
-                            ]);
-                            let g_m = max([
-                                boxed[0].g, boxed[1].g, boxed[2].g, boxed[3].g, boxed[5].g,
-                                boxed[6].g, boxed[7].g, boxed[8].g,
-                            ]);
-                            let b_m = max([
-                                boxed[0].b, boxed[1].b, boxed[2].b, boxed[3].b, boxed[5].b,
-                                boxed[6].b, boxed[7].b, boxed[8].b,
-                            ]);
-
-                            let v_h = Rgb::new(r_m, g_m, b_m);
-
-                            if !ignore_state {
-                                saved.v_high = Some(v_h);
-                            }
-                            v_h
-                        }
-                    };
+            Op::Param(param) => {
+                // Offsets from the centre, with y pointing down
+                let dx = f64::from(x) - f64::from(width.saturating_sub(1)) / 2.0;
+                let dy = f64::from(y) - f64::from(height.saturating_sub(1)) / 2.0;
+                let value = match param {
+                    Param::Dist => {
+                        let corner = f64::from(width.saturating_sub(1))
+                            .hypot(f64::from(height.saturating_sub(1)))
+                            / 2.0;
+                        L::from_unit(if corner > 0.0 {
+                            dx.hypot(dy) / corner
+                        } else {
+                            0.0
+                        })
+                    }
+                    Param::Angle => L::from_turn(dy.atan2(dx) / TAU),
+                    Param::PixelX => L::from_count(x % 256),
+                    Param::PixelY => L::from_count(y % 256),
+                    Param::Width => L::from_count(width),
+                    Param::Height => L::from_count(height),
+                    Param::Frame => L::from_count(frame.0),
+                    Param::Frames => L::from_count(frame.1),
+                    Param::Time => L::from_turn(f64::from(frame.0) / f64::from(frame.1.max(1))),
+                };
+                stack.push([value; 3]);
+            }
 
-                    stack.push(v_h);
-                }
-                'L' => {
-                    let v_l = match saved.v_low {
-                        Some(v_l) => v_l,
-                        None => {
-                            let boxed = fetch_boxed(input, x as i32, y as i32, r, g, b);
-
-                            let r_m = min([
-                                boxed[0].r, boxed[1].r, boxed[2].r, boxed[3].r, boxed[5].r,
-                                boxed[6].r, boxed[7].r, boxed[8].r,
-                            ]);
-                            let g_m = min([
-                                boxed[0].g, boxed[1].g, boxed[2].g, boxed[3].g, boxed[5].g,
-                                boxed[6].g, boxed[7].g, boxed[8].g,
-                            ]);
-                            let b_m = min([
-                                boxed[0].b, boxed[1].b, boxed[2].b, boxed[3].b, boxed[5].b,
-                                boxed[6].b, boxed[7].b, boxed[8].b,
-                            ]);
-
-                            let v_l = Rgb::new(r_m, g_m, b_m);
-                            if !ignore_state {
-                                saved.v_low = Some(v_l);
-                            }
-                            v_l
-                        }
-                    };
+            Op::Brightness(brightness_value) => {
+                let factor = (brightness_value as f64 / 255.0).clamp(0.0, 1.0);
 
-                    stack.push(v_l);
-                }
-                'N' => stack.push(Rgb::new(
-                    rng.gen_range(0..=255),
-                    rng.gen_range(0..=255),
-                    rng.gen_range(0..=255),
-                )),
-                'h' => {
-                    let v_h = match saved.v_h {
-                        Some(v_h) => v_h,
-                        None => {
-                            let h = width.wrapping_sub(x).wrapping_sub(1);
-                            let pixel = get_pixel_in_bounds(h, y);
-
-                            let v_h = Rgb::new(pixel[0], pixel[1], pixel[2]);
-                            if !ignore_state {
-                                saved.v_h = Some(v_h);
-                            }
-                            v_h
-                        }
-                    };
+                let pixel = get_pixel_in_bounds(i64::from(x), i64::from(y));
+                let (nr, ng, nb) = adjust_brightness_hsv(pixel[0], pixel[1], pixel[2], factor);
 
-                    stack.push(v_h);
-                }
-                'v' => {
-                    let v_v = match saved.v_v {
-                        Some(v_v) => v_v,
-                        None => {
-                            let v = height.wrapping_sub(y).wrapping_sub(1);
-                            let pixel = get_pixel_in_bounds(x, v);
-
-                            let v_v = Rgb::new(pixel[0], pixel[1], pixel[2]);
-                            if !ignore_state {
-                                saved.v_v = Some(v_v);
-                            }
-                            v_v
-                        }
-                    };
+                stack.push(lift(Rgb::new(nr, ng, nb)));
+            }
 
-                    stack.push(v_v);
-                }
-                'd' => {
-                    let v_d = match saved.v_d {
-                        Some(v_d) => v_d,
-                        None => {
-                            let x = width.wrapping_sub(x).wrapping_sub(1);
-                            let y = height.wrapping_sub(y).wrapping_sub(1);
-                            let pixel = get_pixel_in_bounds(x, y);
-
-                            let v_d = Rgb::new(pixel[0], pixel[1], pixel[2]);
-                            if !ignore_state {
-                                saved.v_d = Some(v_d);
-                            }
-                            v_d
-                        }
-                    };
+            Op::Invert => {
+                let pixel = get_pixel_in_bounds(i64::from(x), i64::from(y));
+                let mut new_rgba = Rgba([pixel[0], pixel[1], pixel[2], pixel[3]]);
+                new_rgba.invert();
 
-                    stack.push(v_d);
-                }
-                _ => return Err(format!("Unexpected token: {:?}", c)),
-            },
+                stack.push(lift(Rgb::new(new_rgba[0], new_rgba[1], new_rgba[2])));
+            }
 
-            _ => return Err(format!("Unexpected token: {:?}", tok)),
+            Op::Current => stack.push(lift(Rgb::new(r, g, b))),
+            Op::Alpha => stack.push(lift(Rgb::new(a, a, a))),
+            Op::Saved => stack.push(lift(Rgb::new(sr, sg, sb))),
+            Op::Previous => {
+                let [pr, pg, pb, _] = get_previous_pixel(i64::from(x), i64::from(y));
+                stack.push(lift(Rgb::new(pr, pg, pb)));
+            }
+            Op::X => stack.push([L::coord(x, width); 3]),
+            Op::Y => stack.push([L::coord(y, height); 3]),
+            Op::Noise => stack.push(lift(Rgb::new(
+                rng.gen_range(0..=255),
+                rng.gen_range(0..=255),
+                rng.gen_range(0..=255),
+            ))),
         }
     }
 
-    let col = stack.last().ok_or("Stack underflow")?;
-    Ok(Rgba([col.r, col.g, col.b, a]))
+    // Channels without their own statement take the final value, or keep `c`
+    // when the expression is made of per-channel statements only.
+    let col: Value<L> = match stack.last() {
+        Some(col) => *col,
+        None if outputs.iter().any(Option::is_some) => lift(Rgb::new(r, g, b)),
+        None => return Err("Stack underflow".to_string()),
+    };
+    let write = |v: L| match numeric {
+        NumericMode::Saturate | NumericMode::Float => v.saturate(),
+        NumericMode::Byte | NumericMode::Wide => v.wrap(),
+    };
+    let [cr, cg, cb] = space.to_rgb([0, 1, 2].map(|i| write(outputs[i].unwrap_or(col[i]))));
+    Ok(Rgba([cr, cg, cb, outputs[3].map_or(a, write)]))
+}
+
+#[inline]
+fn luminance(r: u8, g: u8, b: u8) -> u8 {
+    f64::from(b).mul_add(0.0722, f64::from(r).mul_add(0.299, f64::from(g) * 0.587)) as u8
 }
 
 #[inline]
-fn fetch_boxed(input: &DynamicImage, x: i32, y: i32, r: u8, g: u8, b: u8) -> [Rgb; 9] {
+fn fetch_boxed(x: i64, y: i64, center: Rgb, get_pixel: impl Fn(i64, i64) -> [u8; 4]) -> [Rgb; 9] {
     let mut k = 0;
 
     let mut boxed: [Rgb; 9] = [Rgb::default(); 9];
 
     for i in x - 1..=x + 1 {
         for j in y - 1..=y + 1 {
-            if i == x && j == y {
-                boxed[k] = Rgb { r, g, b };
-                k += 1;
-                continue;
-            }
-
-            if i < 0 || j < 0 || i as u32 >= input.width() || j as u32 >= input.height() {
-                boxed[k] = Rgb::default();
-                k += 1;
-                continue;
-            }
-
-            let pixel = input.get_pixel(i as u32, j as u32).0;
-            boxed[k] = Rgb {
-                r: pixel[0],
-                g: pixel[1],
-                b: pixel[2],
+            boxed[k] = if i == x && j == y {
+                center
+            } else {
+                let pixel = get_pixel(i, j);
+                Rgb::new(pixel[0], pixel[1], pixel[2])
             };
             k += 1;
         }
@@ -525,17 +581,8 @@ fn gen_random_position<R: RngCore>(min: i32, max: i32, rng: &mut R) -> [(i32, i3
     positions
 }
 
-#[inline]
-fn wrapping_vec_add_u32(a: [u8; 8]) -> u32 {
-    let mut sum: u32 = 0;
-    for i in a {
-        sum = sum.wrapping_add(i as u32);
-    }
-    sum
-}
-
 /// Convert an RGB (0–255) color into HSV, each component in [0.0, 1.0].
-fn rgb_to_hsv(r: u8, g: u8, b: u8) -> (f64, f64, f64) {
+pub(crate) fn rgb_to_hsv(r: u8, g: u8, b: u8) -> (f64, f64, f64) {
     let rf = r as f64 / 255.0;
     let gf = g as f64 / 255.0;
     let bf = b as f64 / 255.0;
@@ -575,7 +622,7 @@ fn rgb_to_hsv(r: u8, g: u8, b: u8) -> (f64, f64, f64) {
 }
 
 /// Convert an HSV color (each in [0.0, 1.0]) back into RGB (0–255).
-fn hsv_to_rgb(h: f64, s: f64, v: f64) -> (u8, u8, u8) {
+pub(crate) fn hsv_to_rgb(h: f64, s: f64, v: f64) -> (u8, u8, u8) {
     let h_deg = h * 360.0;
     let c = v * s;
     let x = c * (1.0 - ((h_deg / 60.0) % 2.0 - 1.0).abs());
@@ -603,3 +650,360 @@ fn adjust_brightness_hsv(r: u8, g: u8, b: u8, factor: f64) -> (u8, u8, u8) {
     let new_v = (v * factor).clamp(0.0, 1.0);
     hsv_to_rgb(h, s, new_v)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::shunting_yard;
+    use image::RgbaImage;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn eval_expr(expr: &str, img: &DynamicImage, position: (u32, u32)) -> [u8; 4] {
+        eval_in(expr, img, EvalContext::at(img, position))
+    }
+
+    /// Like `eval_expr`, with the options of `ctx`.
+    fn eval_in(expr: &str, img: &DynamicImage, ctx: EvalContext<'_>) -> [u8; 4] {
+        let tokens = shunting_yard(expr).unwrap();
+        eval(&tokens, ctx, img, &mut StdRng::seed_from_u64(0))
+            .unwrap()
+            .0
+    }
+
+    fn solid(rgba: [u8; 4]) -> DynamicImage {
+        RgbaImage::from_pixel(4, 4, Rgba(rgba)).into()
+    }
+
+    #[test]
+    fn test_binding_matches_inline() {
+        let img = solid([10, 100, 200, 255]);
+        assert_eq!(
+            eval_expr("a = c ^ 55; (c & a) + 25", &img, (1, 1)),
+            eval_expr("(c & (c ^ 55)) + 25", &img, (1, 1))
+        );
+    }
+
+    #[test]
+    fn test_conditional_is_per_channel() {
+        let img = solid([200, 50, 130, 255]);
+        assert_eq!(
+            eval_expr("if(c ? 128, 255, 0)", &img, (0, 0)),
+            [255, 0, 255, 255]
+        );
+    }
+
+    #[test]
+    fn test_comparisons_and_min_max() {
+        let img = solid([200, 50, 128, 255]);
+        assert_eq!(eval_expr("c lt 128", &img, (0, 0)), [0, 255, 0, 255]);
+        assert_eq!(eval_expr("c <= 128", &img, (0, 0)), [0, 255, 255, 255]);
+        assert_eq!(eval_expr("c == 128", &img, (0, 0)), [0, 0, 255, 255]);
+        assert_eq!(eval_expr("c min 100", &img, (0, 0)), [100, 50, 100, 255]);
+        assert_eq!(eval_expr("c max 100", &img, (0, 0)), [200, 100, 128, 255]);
+    }
+
+    #[test]
+    fn test_function_calls() {
+        let img = solid([200, 50, 128, 255]);
+        assert_eq!(eval_expr("abs(c - 100)", &img, (0, 0)), [100, 50, 28, 255]);
+        assert_eq!(
+            eval_expr("clamp(c, 60, 150)", &img, (0, 0)),
+            [150, 60, 128, 255]
+        );
+        assert_eq!(
+            eval_expr("mix(0, 255, c)", &img, (0, 0)),
+            [200, 50, 128, 255]
+        );
+        assert_eq!(eval_expr("avg(c, 0)", &img, (0, 0)), [100, 25, 64, 255]);
+        assert_eq!(
+            eval_expr("max(c, 60, 100)", &img, (0, 0)),
+            [200, 100, 128, 255]
+        );
+        assert_eq!(
+            eval_expr("sqrt(0) + sin(0)", &img, (0, 0)),
+            [128, 128, 128, 255]
+        );
+    }
+
+    #[test]
+    fn test_swizzle() {
+        let img = solid([10, 20, 30, 255]);
+        assert_eq!(eval_expr("c.gbr", &img, (0, 0)), [20, 30, 10, 255]);
+        assert_eq!(eval_expr("c.b", &img, (0, 0)), [30, 30, 30, 255]);
+        assert_eq!(eval_expr("(c + 1).rrr", &img, (0, 0)), [11, 11, 11, 255]);
+    }
+
+    #[test]
+    fn test_channel_statements() {
+        let img = solid([10, 20, 30, 255]);
+        assert_eq!(
+            eval_expr("r: c.b; g: 200;", &img, (0, 0)),
+            [30, 200, 30, 255]
+        );
+        // Channels without a statement take the final expression
+        assert_eq!(eval_expr("r: 0; c + 1", &img, (0, 0)), [0, 21, 31, 255]);
+    }
+
+    #[test]
+    fn test_alpha() {
+        let img = solid([10, 20, 30, 200]);
+        assert_eq!(eval_expr("A", &img, (0, 0)), [200, 200, 200, 200]);
+        assert_eq!(eval_expr("a: A > 1; c", &img, (0, 0)), [10, 20, 30, 100]);
+        assert_eq!(eval_expr("a: c", &img, (0, 0)), [10, 20, 30, 20]);
+
+        // Transparent pixels are evaluated unless skipped
+        let img = solid([10, 20, 30, 0]);
+        assert_eq!(eval_expr("a: 255; c + 1", &img, (0, 0)), [11, 21, 31, 255]);
+    }
+
+    #[test]
+    fn test_offset_sampling() {
+        let mut img = RgbaImage::from_pixel(4, 4, Rgba([0, 0, 0, 255]));
+        img.put_pixel(3, 1, Rgba([10, 20, 30, 255]));
+        let img: DynamicImage = img.into();
+
+        assert_eq!(eval_expr("c[2, -1]", &img, (1, 2)), [10, 20, 30, 255]);
+        assert_eq!(eval_expr("h[-1, 1]", &img, (1, 0)), [10, 20, 30, 255]);
+        // Per-channel offsets: only red reads the bright pixel
+        assert_eq!(eval_expr("c[R2, 0]", &img, (1, 1)), [10, 0, 0, 255]);
+        // Out of bounds reads are transparent black by default
+        assert_eq!(eval_expr("c[5, 0] + A[5, 0]", &img, (1, 1)), [0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn test_edge_modes() {
+        let mut img = RgbaImage::from_pixel(3, 3, Rgba([0, 0, 0, 255]));
+        img.put_pixel(0, 1, Rgba([90, 90, 90, 255]));
+        img.put_pixel(2, 1, Rgba([30, 30, 30, 255]));
+        let img: DynamicImage = img.into();
+
+        let eval_edge = |expr: &str, edge: EdgeMode| {
+            let ctx = EvalContext {
+                edge,
+                ..EvalContext::at(&img, (2, 1))
+            };
+            eval_in(expr, &img, ctx)[0]
+        };
+
+        assert_eq!(eval_edge("c[1, 0]", EdgeMode::Zero), 0);
+        assert_eq!(eval_edge("c[1, 0]", EdgeMode::Clamp), 30);
+        assert_eq!(eval_edge("c[1, 0]", EdgeMode::Wrap), 90);
+        assert_eq!(eval_edge("c[1, 0]", EdgeMode::Mirror), 30);
+        // Neighbourhood operands follow the edge mode too
+        assert_eq!(eval_edge("H", EdgeMode::Zero), 0);
+        assert_eq!(eval_edge("H", EdgeMode::Wrap), 90);
+    }
+
+    #[test]
+    fn test_blur() {
+        let mut img = RgbaImage::from_pixel(5, 5, Rgba([0, 0, 0, 255]));
+        img.put_pixel(2, 2, Rgba([90, 180, 255, 255]));
+        let img: DynamicImage = img.into();
+
+        assert_eq!(eval_expr("k", &img, (1, 1)), [10, 20, 28, 255]);
+        assert_eq!(eval_expr("k1", &img, (2, 2)), [10, 20, 28, 255]);
+        assert_eq!(eval_expr("k2", &img, (0, 0)), [3, 7, 10, 255]);
+        assert_eq!(eval_expr("k1", &img, (0, 0)), [0, 0, 0, 255]);
+        // `b` is still brightness
+        assert_eq!(eval_expr("b", &img, (2, 2)), [90, 180, 255, 255]);
+    }
+
+    #[test]
+    fn test_unary_operators() {
+        let img = solid([10, 20, 30, 255]);
+        assert_eq!(eval_expr("~c", &img, (0, 0)), [245, 235, 225, 255]);
+        assert_eq!(eval_expr("-c", &img, (0, 0)), [246, 236, 226, 255]);
+        // Unlike `i`, `~` applies to whatever is on its right
+        assert_eq!(eval_expr("~(c + 5)", &img, (0, 0)), [240, 230, 220, 255]);
+        assert_eq!(
+            eval_expr("bright(c + 10, 255)", &img, (0, 0)),
+            [20, 30, 40, 255]
+        );
+        assert_eq!(eval_expr("bright(c, 0)", &img, (0, 0)), [0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn test_hsv_functions() {
+        let img = solid([255, 0, 0, 255]);
+        assert_eq!(eval_expr("hue(c)", &img, (0, 0)), [0, 0, 0, 255]);
+        assert_eq!(eval_expr("sat(c)", &img, (0, 0)), [255, 255, 255, 255]);
+        assert_eq!(eval_expr("val(c)", &img, (0, 0)), [255, 255, 255, 255]);
+        // Rotating red by a third of the wheel gives green
+        assert_eq!(
+            eval_expr("hsv(hue(c) + 85, sat(c), val(c))", &img, (0, 0)),
+            [2, 255, 0, 255]
+        );
+
+        // Round trips up to hue quantization
+        let img = solid([40, 200, 120, 255]);
+        assert_eq!(
+            eval_expr("hsv(hue(c), sat(c), val(c))", &img, (0, 0)),
+            [40, 200, 121, 255]
+        );
+        // Saturation crush
+        assert_eq!(
+            eval_expr("hsv(hue(c), 0, val(c))", &img, (0, 0)),
+            [200, 200, 200, 255]
+        );
+    }
+
+    #[test]
+    fn test_color_space() {
+        // [200, 50, 100] is Y 101, Cb 128, Cr 199; inverting Y gives 154, which
+        // converts back to R = 154 + 1.402 * 71, G = 154 - 0.714136 * 71, B = 154
+        let img = solid([200, 50, 100, 255]);
+        assert_eq!(
+            eval_expr("use ycbcr; r: 255 - c;", &img, (0, 0)),
+            [254, 103, 154, 255]
+        );
+
+        let img = solid([100, 100, 100, 255]);
+        // Chroma planes of a grey pixel sit at 128
+        assert_eq!(
+            eval_expr("use ycbcr; c.g", &img, (0, 0)),
+            [128, 128, 128, 255]
+        );
+        assert_eq!(eval_expr("use hsv; c", &img, (0, 0)), [100, 100, 100, 255]);
+        assert_eq!(eval_expr("use lab; c", &img, (0, 0)), [100, 100, 100, 255]);
+    }
+
+    #[test]
+    fn test_numeric_modes() {
+        let img = solid([200, 200, 200, 255]);
+        assert_eq!(eval_expr("(c + c) / 2", &img, (0, 0)), [72, 72, 72, 255]);
+        assert_eq!(
+            eval_expr("use wide; (c + c) / 2", &img, (0, 0)),
+            [200, 200, 200, 255]
+        );
+        // Only the written value is wrapped or clamped
+        assert_eq!(
+            eval_expr("use wide; c + 100", &img, (0, 0)),
+            [44, 44, 44, 255]
+        );
+        assert_eq!(
+            eval_expr("use saturate; c + 100", &img, (0, 0)),
+            [255, 255, 255, 255]
+        );
+        assert_eq!(
+            eval_expr("use saturate; c - 250", &img, (0, 0)),
+            [0, 0, 0, 255]
+        );
+        assert_eq!(
+            eval_expr("use saturate; ~c", &img, (0, 0)),
+            [55, 55, 55, 255]
+        );
+        assert_eq!(
+            eval_expr("use saturate; c[-1, 0] + 1", &img, (1, 0)),
+            [201, 201, 201, 255]
+        );
+    }
+
+    #[test]
+    fn test_float_dialect() {
+        let img = solid([200, 200, 200, 255]);
+        assert_eq!(
+            eval_expr("use float; c * 0.5", &img, (0, 0)),
+            [100, 100, 100, 255]
+        );
+        assert_eq!(
+            eval_expr("use float; (c + c) / 2", &img, (0, 0)),
+            [200, 200, 200, 255]
+        );
+        assert_eq!(
+            eval_expr("use float; c & 0.5", &img, (0, 0)),
+            [128, 128, 128, 255]
+        );
+        // Coordinates span exactly 0 to 1
+        assert_eq!(eval_expr("use float; x", &img, (0, 0)), [0, 0, 0, 255]);
+        assert_eq!(
+            eval_expr("use float; x", &img, (3, 0)),
+            [255, 255, 255, 255]
+        );
+        assert_eq!(eval_expr("use float; 1 - y", &img, (0, 3)), [0, 0, 0, 255]);
+        assert_eq!(
+            eval_expr("use float; sin(0.25)", &img, (0, 0)),
+            [255, 255, 255, 255]
+        );
+        // Literals aren't limited to a byte
+        assert_eq!(
+            eval_expr("use float; 1000 - 999.5", &img, (0, 0)),
+            [128, 128, 128, 255]
+        );
+        assert_eq!(
+            eval_expr("use float; c * 300.5 / 1000", &img, (0, 0)),
+            [60, 60, 60, 255]
+        );
+        // Decimals are rounded to whole numbers in the integer modes
+        assert_eq!(eval_expr("2.4 + 0.6", &img, (0, 0)), [3, 3, 3, 255]);
+    }
+
+    #[test]
+    fn test_polar_functions() {
+        let img = solid([0, 0, 0, 255]);
+        assert_eq!(eval_expr("hypot(3, 4)", &img, (0, 0)), [5, 5, 5, 255]);
+        assert_eq!(eval_expr("atan(1, 0)", &img, (0, 0)), [64, 64, 64, 255]);
+        // Byte arguments are read as signed
+        assert_eq!(eval_expr("atan(0, -1)", &img, (0, 0)), [128, 128, 128, 255]);
+        assert_eq!(
+            eval_expr("use float; hypot(0.3, 0.4)", &img, (0, 0)),
+            [128, 128, 128, 255]
+        );
+    }
+
+    #[test]
+    fn test_position_params() {
+        let img: DynamicImage = RgbaImage::from_pixel(5, 5, Rgba([0, 0, 0, 255])).into();
+        assert_eq!(eval_expr("dist", &img, (2, 2)), [0, 0, 0, 255]);
+        assert_eq!(eval_expr("dist", &img, (0, 0)), [255, 255, 255, 255]);
+        assert_eq!(eval_expr("angle", &img, (4, 2)), [0, 0, 0, 255]);
+        assert_eq!(eval_expr("angle", &img, (2, 4)), [64, 64, 64, 255]);
+        assert_eq!(eval_expr("angle", &img, (0, 2)), [128, 128, 128, 255]);
+        assert_eq!(eval_expr("px + py * 10", &img, (3, 1)), [13, 13, 13, 255]);
+        assert_eq!(
+            eval_expr("width - height + width", &img, (0, 0)),
+            [5, 5, 5, 255]
+        );
+        assert_eq!(
+            eval_expr("use float; dist", &img, (4, 4)),
+            [255, 255, 255, 255]
+        );
+    }
+
+    #[test]
+    fn test_frame_params() {
+        let img = solid([0, 0, 0, 255]);
+        let eval_frame = |expr: &str, frame: (u32, u32)| {
+            let ctx = EvalContext {
+                frame,
+                ..EvalContext::at(&img, (0, 0))
+            };
+            eval_in(expr, &img, ctx)[0]
+        };
+
+        assert_eq!(eval_frame("T", (0, 1)), 0);
+        assert_eq!(eval_frame("T", (2, 4)), 128);
+        assert_eq!(eval_frame("frame * 10 + frames", (2, 4)), 24);
+        assert_eq!(eval_frame("use float; T", (1, 4)), 64);
+    }
+
+    #[test]
+    fn test_previous_frame() {
+        let img = solid([10, 10, 10, 255]);
+        let previous = solid([90, 90, 90, 255]);
+        let eval_previous = |expr: &str, previous: Option<&DynamicImage>| {
+            let ctx = EvalContext {
+                frame: (1, 2),
+                previous,
+                ..EvalContext::at(&img, (1, 1))
+            };
+            eval_in(expr, &img, ctx)[0]
+        };
+
+        assert_eq!(eval_previous("p", Some(&previous)), 90);
+        assert_eq!(eval_previous("p[1, 0] - c", Some(&previous)), 80);
+        assert_eq!(eval_previous("p[5, 0]", Some(&previous)), 0);
+        // The first frame reads the input instead
+        assert_eq!(eval_previous("p", None), 10);
+    }
+}
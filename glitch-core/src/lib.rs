@@ -1,80 +1,202 @@
 #![deny(clippy::perf, clippy::correctness)]
 #![warn(rust_2018_idioms, clippy::complexity, clippy::nursery)]
 
+pub mod ast;
+pub mod blur;
 pub mod bounds;
 pub mod classify;
+pub mod edge;
+pub mod error;
 pub mod eval;
+pub mod numeric;
+pub mod optimize;
 pub mod parser;
+pub mod pipeline;
+pub mod program;
 pub mod rgb;
+pub mod space;
+pub mod state;
 pub mod token;
 
 pub use classify::{classify, Classification};
+pub use edge::EdgeMode;
+pub use error::{GlitchError, Span};
 pub use eval::EvalContext;
-pub use token::Token;
+pub use numeric::NumericMode;
+pub use optimize::optimize;
+pub use pipeline::Pipeline;
+pub use program::Program;
+pub use token::{Func, Param, Pragma, Token};
 pub use rgb::Rgb;
+pub use space::ColorSpace;
+pub use state::StateMode;
 
 /// Result of a successful expression verification.
 #[derive(Debug, Clone)]
 pub struct VerifyResult {
     /// The parsed RPN token list.
     pub tokens: Vec<Token>,
+    /// Source span of each token.
+    pub spans: Vec<Span>,
     /// Human-readable description of each token (via Display).
     pub token_descriptions: Vec<String>,
     /// Number of tokens in the compiled expression.
     pub token_count: usize,
 }
 
+impl VerifyResult {
+    /// Number of tokens after [`optimize`] in `numeric` mode, or the mode the
+    /// expression picks with `use <mode>;`.
+    pub fn optimized_token_count(&self, numeric: NumericMode) -> usize {
+        optimize(&self.tokens, numeric).len()
+    }
+}
+
 /// Parse and verify an expression without needing any image data.
 ///
 /// This performs two levels of validation:
 /// 1. **Syntax** — Shunting Yard parsing (balanced parens, valid tokens, number ranges)
-/// 2. **Semantics** — Stack simulation (no underflows, exactly one result)
+/// 2. **Semantics** — Stack simulation (no underflows, bindings assigned
+///    before use, exactly one result)
 ///
 /// # Errors
-/// Returns a human-readable error string if:
-/// - The expression has invalid syntax (from parser)
+/// Returns every syntax error from the parser, or otherwise the first of these
+/// problems, each as a [`GlitchError`] pointing into `expr`:
 /// - An operator would underflow the stack (not enough operands)
+/// - A binding is loaded before it has been stored
+/// - A function is called with the wrong number of arguments
 /// - The expression produces zero or multiple results
+/// - A binding or per-channel statement doesn't produce exactly one value
+///   (wrapped in [`GlitchError::Statement`], e.g. `Channel 'g': ...` or
+///   `Binding 'edge': ...`)
 ///
 /// # Example
 /// ```
 /// let result = glitch_core::verify("128 & (c - 150)").unwrap();
 /// assert_eq!(result.token_count, 5); // [128, c, 150, -, &]
+///
+/// // Constants are folded when optimizing, with the arithmetic of the numeric mode
+/// use glitch_core::NumericMode;
+/// let result = glitch_core::verify("c + (3 - 5) / 2").unwrap();
+/// assert_eq!(result.token_count, 7);
+/// assert_eq!(result.optimized_token_count(NumericMode::Byte), 3);
+/// assert_eq!(result.optimized_token_count(NumericMode::Wide), 7);
+///
+/// // Bindings compute a value once and reuse it
+/// let result = glitch_core::verify("a = c ^ 55; (a & s) + a").unwrap();
+/// assert_eq!(result.token_count, 9);
+///
+/// // Per-channel expressions are validated channel by channel
+/// let err = glitch_core::verify("r: c ^ x; g: h +; b: s & 128;").unwrap_err();
+/// assert!(err[0].to_string().starts_with("Channel 'g'"));
+/// assert_eq!(err[0].span(), Some(15..16)); // the `+`
+///
+/// // Bindings are labelled with the name they were given
+/// let err = glitch_core::verify("edge = clamp(c, 16); edge").unwrap_err();
+/// assert!(err[0].to_string().starts_with("Binding 'edge': 'clamp' expects"));
+///
+/// // Every syntax error is reported at once
+/// let errors = glitch_core::verify("(c + ) h)").unwrap_err();
+/// assert_eq!(errors.len(), 3);
+///
+/// // Function arity is checked by name
+/// assert!(glitch_core::verify("clamp(c, 16)").is_err());
 /// ```
-pub fn verify(expr: &str) -> Result<VerifyResult, String> {
+pub fn verify(expr: &str) -> Result<VerifyResult, Vec<GlitchError>> {
     // Phase 1: Syntax — parse into RPN tokens
-    let tokens = parser::shunting_yard(expr)?;
+    let (tokens, spans): (Vec<Token>, Vec<Span>) =
+        parser::shunting_yard_spanned(expr)?.into_iter().unzip();
 
     // Phase 2: Semantics — simulate the evaluation stack
     let mut depth: i32 = 0;
+    let mut bound = [false; 256];
+    let mut has_outputs = false;
+    // First token of the current statement
+    let mut statement = 0;
 
     for (i, tok) in tokens.iter().enumerate() {
         let (pops, pushes) = stack_effect(tok);
+        let span = spans[i].clone();
+
+        match tok {
+            Token::Call(func, args) if !func.accepts(*args) => {
+                return Err(vec![in_statement(
+                    (expr, &tokens, &spans),
+                    i,
+                    GlitchError::Arity {
+                        name: func.name().to_string(),
+                        expected: func.arity_description(),
+                        found: *args as usize,
+                        span,
+                    },
+                )]);
+            }
+            Token::Load(slot) if !bound[*slot as usize] => {
+                return Err(vec![in_statement(
+                    (expr, &tokens, &spans),
+                    i,
+                    GlitchError::syntax(
+                        format!(
+                            "Binding '{}' loaded before it was assigned",
+                            &expr[span.clone()]
+                        ),
+                        span,
+                    ),
+                )]);
+            }
+            // Every statement starts from an empty stack and must leave exactly one value
+            Token::Store(_) | Token::Output(_) if depth == 0 => {
+                return Err(vec![in_statement(
+                    (expr, &tokens, &spans),
+                    i,
+                    GlitchError::NoResult { span },
+                )]);
+            }
+            Token::Store(_) | Token::Output(_) if depth > 1 => {
+                return Err(vec![in_statement(
+                    (expr, &tokens, &spans),
+                    i,
+                    GlitchError::ExtraValues {
+                        count: depth as usize,
+                        span: covering(&tokens[statement..=i], &spans[statement..=i]),
+                    },
+                )]);
+            }
+            Token::Store(slot) => bound[*slot as usize] = true,
+            Token::Output(_) => has_outputs = true,
+            _ => {}
+        }
 
         if depth < pops {
-            return Err(format!(
-                "Stack underflow at token {} ({}): needs {} operand{} but stack has {}",
-                i + 1,
-                tok,
-                pops,
-                if pops == 1 { "" } else { "s" },
-                depth
-            ));
+            return Err(vec![in_statement(
+                (expr, &tokens, &spans),
+                i,
+                GlitchError::StackUnderflow {
+                    token: tok.to_string(),
+                    needs: pops as usize,
+                    found: depth as usize,
+                    span,
+                },
+            )]);
         }
 
         depth -= pops;
         depth += pushes;
+        if matches!(tok, Token::Store(_) | Token::Output(_)) {
+            statement = i + 1;
+        }
     }
 
-    if depth == 0 {
-        return Err("Expression produces no result (empty or all operators)".to_string());
+    // Per-channel expressions may leave no default value; unassigned channels keep `c`
+    if depth == 0 && !has_outputs {
+        return Err(vec![GlitchError::NoResult { span: 0..expr.len() }]);
     }
 
     if depth > 1 {
-        return Err(format!(
-            "Expression produces {} values instead of 1 — missing operator(s) between values",
-            depth
-        ));
+        return Err(vec![GlitchError::ExtraValues {
+            count: depth as usize,
+            span: covering(&tokens[statement..], &spans[statement..]),
+        }]);
     }
 
     let token_descriptions = tokens.iter().map(|t| format!("{}", t)).collect();
@@ -82,21 +204,73 @@ pub fn verify(expr: &str) -> Result<VerifyResult, String> {
 
     Ok(VerifyResult {
         tokens,
+        spans,
         token_descriptions,
         token_count,
     })
 }
 
+/// Whether the tokens read the previous animation frame with `p`, in which
+/// case frames have to be processed in order.
+pub fn reads_previous_frame(tokens: &[Token]) -> bool {
+    tokens
+        .iter()
+        .any(|t| matches!(t, Token::Char('p') | Token::Sample('p')))
+}
+
+/// Labels `error` with the statement that token `index` belongs to, found from
+/// the `Store` or `Output` that ends it. Bindings are named as written in `expr`,
+/// which the span of their `Store` points at. Errors in the final expression are
+/// returned unchanged.
+fn in_statement(
+    (expr, tokens, spans): (&str, &[Token], &[Span]),
+    index: usize,
+    error: GlitchError,
+) -> GlitchError {
+    let end =
+        (index..tokens.len()).find(|&i| matches!(tokens[i], Token::Store(_) | Token::Output(_)));
+
+    let label = match end.map(|i| (tokens[i], &spans[i])) {
+        Some((Token::Output(channel), _)) => {
+            format!("Channel '{}'", token::channel_label(channel))
+        }
+        Some((Token::Store(_), span)) => format!("Binding '{}'", &expr[span.clone()]),
+        _ => return error,
+    };
+    GlitchError::Statement {
+        label,
+        error: Box::new(error),
+    }
+}
+
+/// The smallest span covering every token of a statement, leaving out pragmas.
+fn covering(tokens: &[Token], spans: &[Span]) -> Span {
+    let mut statement = tokens
+        .iter()
+        .zip(spans)
+        .filter(|(t, _)| !matches!(t, Token::Pragma(_)))
+        .map(|(_, span)| span);
+    let first = statement.next().cloned().unwrap_or_default();
+    statement.fold(first, |all, span| all.start.min(span.start)..all.end.max(span.end))
+}
+
 /// Returns (pops, pushes) for each token's stack effect.
-const fn stack_effect(tok: &Token) -> (i32, i32) {
+pub(crate) const fn stack_effect(tok: &Token) -> (i32, i32) {
     match tok {
         // Values — push 1
         Token::Num(_)
+        | Token::Float(_)
         | Token::Char(_)
+        | Token::Param(_)
         | Token::Random(_)
         | Token::RGBColor(_)
         | Token::Brightness(_)
-        | Token::Invert => (0, 1),
+        | Token::Blur(_)
+        | Token::Invert
+        | Token::Load(_) => (0, 1),
+
+        // Bindings and channel outputs — pop the stored value
+        Token::Store(_) | Token::Output(_) => (1, 0),
 
         // Binary operators — pop 2, push 1
         Token::Add
@@ -112,8 +286,30 @@ const fn stack_effect(tok: &Token) -> (i32, i32) {
         | Token::BitLShift
         | Token::BitRShift
         | Token::Greater
+        | Token::Less
+        | Token::GreaterEqual
+        | Token::LessEqual
+        | Token::Equal
+        | Token::NotEqual
+        | Token::Min
+        | Token::Max
         | Token::Weight => (2, 1),
 
+        // Offset sampling — pop dx and dy, push the sampled value
+        Token::Sample(_) => (2, 1),
+
+        // Unary operators — pop 1, push 1
+        Token::Swizzle(_) | Token::Not | Token::Neg => (1, 1),
+
+        // Ternary operators — pop 3, push 1
+        Token::If => (3, 1),
+
+        // Function calls — pop their arguments, push 1
+        Token::Call(_, args) => (*args as i32, 1),
+
+        // Pragmas only configure evaluation
+        Token::Pragma(_) => (0, 0),
+
         // Parens should never appear in RPN output, but be safe
         Token::LeftParen | Token::RightParen => (0, 0),
     }
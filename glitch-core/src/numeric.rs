@@ -0,0 +1,570 @@
+use serde::{Deserialize, Serialize};
+
+/// How intermediate values are carried while an expression runs.
+///
+/// Operands are always read as bytes; the mode decides what the operators do
+/// with them and how the final value is written back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub enum NumericMode {
+    /// Every operator wraps at `u8`, like go-glitch.
+    #[default]
+    Byte,
+    /// Intermediates are `i32`; the final value wraps to a byte when written.
+    Wide,
+    /// Intermediates are `i32`; the final value is clamped to `0..=255` when written.
+    Saturate,
+    /// Values are `f32` in `0.0..=1.0`, literals are plain numbers (`0.25`, `2`)
+    /// and `x`/`y` are normalised coordinates. Written values are clamped.
+    Float,
+}
+
+impl NumericMode {
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Byte => "byte",
+            Self::Wide => "wide",
+            Self::Saturate => "saturate",
+            Self::Float => "float",
+        }
+    }
+}
+
+impl std::str::FromStr for NumericMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "byte" => Ok(Self::Byte),
+            "wide" => Ok(Self::Wide),
+            "saturate" => Ok(Self::Saturate),
+            "float" => Ok(Self::Float),
+            _ => Err(format!(
+                "Unknown numeric mode '{}' (expected byte, wide, saturate or float)",
+                s
+            )),
+        }
+    }
+}
+
+/// One channel of a value on the evaluation stack.
+pub(crate) trait Lane: Copy + PartialOrd + Default {
+    /// Result of a comparison that holds.
+    const TRUE: Self;
+
+    /// Value of an operand read as the byte `v`.
+    fn from_byte(v: u8) -> Self;
+    /// Value of the integer literal `n`.
+    fn from_literal(n: u8) -> Self;
+    /// Value of the decimal literal `v`, rounded to the nearest whole number
+    /// outside the float dialect.
+    fn from_decimal(v: f32) -> Self;
+    /// A pixel count, such as the image width. Wraps at 256 in byte mode.
+    fn from_count(n: u32) -> Self;
+    /// Position `v` along an axis of `len` pixels, for `x` and `y`.
+    fn coord(v: u32, len: u32) -> Self;
+    /// Byte written for this value, wrapping out of range values.
+    fn wrap(self) -> u8;
+    /// Byte written for this value, clamping out of range values.
+    fn saturate(self) -> u8;
+    /// Pixel offset for `src[dx, dy]`.
+    fn offset(self) -> i64;
+
+    fn add(self, rhs: Self) -> Self;
+    fn sub(self, rhs: Self) -> Self;
+    fn mul(self, rhs: Self) -> Self;
+    /// Division by zero leaves `self` unchanged.
+    fn div(self, rhs: Self) -> Self;
+    /// Remainder by zero leaves `self` unchanged.
+    fn rem(self, rhs: Self) -> Self;
+    fn pow(self, rhs: Self) -> Self;
+    fn shl(self, rhs: Self) -> Self;
+    fn shr(self, rhs: Self) -> Self;
+    fn bitand(self, rhs: Self) -> Self;
+    fn bitor(self, rhs: Self) -> Self;
+    fn bitxor(self, rhs: Self) -> Self;
+    fn and_not(self, rhs: Self) -> Self;
+    /// Scales `self` by `rhs` as a fraction of full scale.
+    fn weight(self, rhs: Self) -> Self;
+    fn not(self) -> Self;
+    fn neg(self) -> Self;
+    fn abs(self) -> Self;
+
+    /// Blends from `self` to `other` as `t` goes from 0 to 255.
+    fn mix(self, other: Self, t: Self) -> Self;
+    fn mean(values: impl IntoIterator<Item = Self>) -> Self;
+    /// Maps `0..=255` onto `0.0..=1.0`.
+    fn to_unit(self) -> f64;
+    fn from_unit(v: f64) -> Self;
+    /// Like `to_unit`, but reading bytes as signed so differences keep their direction.
+    fn to_signed_unit(self) -> f64;
+    /// Angles are fractions of a full turn, which for bytes spans the whole `0..=255`.
+    fn from_turn(t: f64) -> Self;
+    fn to_turn(self) -> f64;
+
+    fn is_true(self) -> bool {
+        self != Self::default()
+    }
+}
+
+impl Lane for u8 {
+    const TRUE: Self = 255;
+
+    fn from_byte(v: u8) -> Self {
+        v
+    }
+
+    fn from_literal(n: u8) -> Self {
+        n
+    }
+
+    fn from_decimal(v: f32) -> Self {
+        v.round().clamp(0.0, 255.0) as Self
+    }
+
+    fn from_count(n: u32) -> Self {
+        n as Self
+    }
+
+    fn coord(v: u32, len: u32) -> Self {
+        (((255 * v) / len) & 255) as Self
+    }
+
+    fn wrap(self) -> u8 {
+        self
+    }
+
+    fn saturate(self) -> u8 {
+        self
+    }
+
+    fn offset(self) -> i64 {
+        i64::from(self as i8)
+    }
+
+    fn add(self, rhs: Self) -> Self {
+        self.wrapping_add(rhs)
+    }
+
+    fn sub(self, rhs: Self) -> Self {
+        self.wrapping_sub(rhs)
+    }
+
+    fn mul(self, rhs: Self) -> Self {
+        self.wrapping_mul(rhs)
+    }
+
+    fn div(self, rhs: Self) -> Self {
+        if rhs == 0 {
+            return self;
+        }
+        self.wrapping_div(rhs)
+    }
+
+    fn rem(self, rhs: Self) -> Self {
+        if rhs == 0 {
+            return self;
+        }
+        self.wrapping_rem(rhs)
+    }
+
+    fn pow(self, rhs: Self) -> Self {
+        self.wrapping_pow(rhs.into())
+    }
+
+    fn shl(self, rhs: Self) -> Self {
+        self.wrapping_shl(rhs.into())
+    }
+
+    fn shr(self, rhs: Self) -> Self {
+        self.wrapping_shr(rhs.into())
+    }
+
+    fn bitand(self, rhs: Self) -> Self {
+        self & rhs
+    }
+
+    fn bitor(self, rhs: Self) -> Self {
+        self | rhs
+    }
+
+    fn bitxor(self, rhs: Self) -> Self {
+        self ^ rhs
+    }
+
+    fn and_not(self, rhs: Self) -> Self {
+        self & !rhs
+    }
+
+    fn weight(self, rhs: Self) -> Self {
+        (f64::from(self) * (f64::from(rhs) / 255.0)) as Self
+    }
+
+    fn not(self) -> Self {
+        !self
+    }
+
+    fn neg(self) -> Self {
+        self.wrapping_neg()
+    }
+
+    // Reads the value as signed so `abs(a - b)` is the distance for small differences
+    fn abs(self) -> Self {
+        (self as i8).unsigned_abs()
+    }
+
+    fn mix(self, other: Self, t: Self) -> Self {
+        let t = u32::from(t);
+        ((u32::from(self) * (255 - t) + u32::from(other) * t) / 255) as Self
+    }
+
+    fn mean(values: impl IntoIterator<Item = Self>) -> Self {
+        let mut count = 0;
+        let sum: u32 = values.into_iter().inspect(|_| count += 1).map(u32::from).sum();
+        (sum / count) as Self
+    }
+
+    fn to_unit(self) -> f64 {
+        f64::from(self) / 255.0
+    }
+
+    fn from_unit(v: f64) -> Self {
+        (v * 255.0).round().clamp(0.0, 255.0) as Self
+    }
+
+    fn to_signed_unit(self) -> f64 {
+        f64::from(self as i8) / 255.0
+    }
+
+    fn from_turn(t: f64) -> Self {
+        ((t.rem_euclid(1.0) * 256.0).round() as u32 % 256) as Self
+    }
+
+    fn to_turn(self) -> f64 {
+        f64::from(self) / 256.0
+    }
+}
+
+/// Used by the wide and saturating modes. Operators saturate at the `i32`
+/// bounds instead of wrapping, and `~` flips the low byte only so `~c` is
+/// still `255 - c`.
+impl Lane for i32 {
+    const TRUE: Self = 255;
+
+    fn from_byte(v: u8) -> Self {
+        Self::from(v)
+    }
+
+    fn from_literal(n: u8) -> Self {
+        Self::from(n)
+    }
+
+    fn from_decimal(v: f32) -> Self {
+        v.round() as Self
+    }
+
+    fn from_count(n: u32) -> Self {
+        n.try_into().unwrap_or(Self::MAX)
+    }
+
+    fn coord(v: u32, len: u32) -> Self {
+        u8::coord(v, len).into()
+    }
+
+    fn wrap(self) -> u8 {
+        self as u8
+    }
+
+    fn saturate(self) -> u8 {
+        self.clamp(0, 255) as u8
+    }
+
+    fn offset(self) -> i64 {
+        i64::from(self)
+    }
+
+    fn add(self, rhs: Self) -> Self {
+        self.saturating_add(rhs)
+    }
+
+    fn sub(self, rhs: Self) -> Self {
+        self.saturating_sub(rhs)
+    }
+
+    fn mul(self, rhs: Self) -> Self {
+        self.saturating_mul(rhs)
+    }
+
+    fn div(self, rhs: Self) -> Self {
+        if rhs == 0 {
+            return self;
+        }
+        self.wrapping_div(rhs)
+    }
+
+    fn rem(self, rhs: Self) -> Self {
+        if rhs == 0 {
+            return self;
+        }
+        self.wrapping_rem(rhs)
+    }
+
+    fn pow(self, rhs: Self) -> Self {
+        self.saturating_pow(rhs.max(0) as u32)
+    }
+
+    fn shl(self, rhs: Self) -> Self {
+        u32::try_from(rhs)
+            .ok()
+            .and_then(|n| self.checked_shl(n))
+            .unwrap_or(0)
+    }
+
+    fn shr(self, rhs: Self) -> Self {
+        u32::try_from(rhs)
+            .ok()
+            .and_then(|n| self.checked_shr(n))
+            .unwrap_or(0)
+    }
+
+    fn bitand(self, rhs: Self) -> Self {
+        self & rhs
+    }
+
+    fn bitor(self, rhs: Self) -> Self {
+        self | rhs
+    }
+
+    fn bitxor(self, rhs: Self) -> Self {
+        self ^ rhs
+    }
+
+    fn and_not(self, rhs: Self) -> Self {
+        self & !rhs
+    }
+
+    fn weight(self, rhs: Self) -> Self {
+        (f64::from(self) * (f64::from(rhs) / 255.0)) as Self
+    }
+
+    fn not(self) -> Self {
+        self ^ 0xFF
+    }
+
+    fn neg(self) -> Self {
+        self.saturating_neg()
+    }
+
+    fn abs(self) -> Self {
+        self.saturating_abs()
+    }
+
+    fn mix(self, other: Self, t: Self) -> Self {
+        let t = i64::from(t);
+        let v = (i64::from(self) * (255 - t) + i64::from(other) * t) / 255;
+        v.clamp(Self::MIN.into(), Self::MAX.into()) as Self
+    }
+
+    fn mean(values: impl IntoIterator<Item = Self>) -> Self {
+        let mut count = 0;
+        let sum: i64 = values.into_iter().inspect(|_| count += 1).map(i64::from).sum();
+        (sum / count) as Self
+    }
+
+    fn to_unit(self) -> f64 {
+        f64::from(self) / 255.0
+    }
+
+    fn from_unit(v: f64) -> Self {
+        (v * 255.0).round() as Self
+    }
+
+    fn to_signed_unit(self) -> f64 {
+        self.to_unit()
+    }
+
+    fn from_turn(t: f64) -> Self {
+        u8::from_turn(t).into()
+    }
+
+    fn to_turn(self) -> f64 {
+        f64::from(self) / 256.0
+    }
+}
+
+/// Used by the float dialect. Bitwise operators act on the 8-bit form of
+/// their operands (`c & 0.5`), while shift amounts are plain counts.
+impl Lane for f32 {
+    const TRUE: Self = 1.0;
+
+    fn from_byte(v: u8) -> Self {
+        Self::from(v) / 255.0
+    }
+
+    fn from_literal(n: u8) -> Self {
+        Self::from(n)
+    }
+
+    fn from_decimal(v: f32) -> Self {
+        v
+    }
+
+    fn from_count(n: u32) -> Self {
+        n as Self
+    }
+
+    fn coord(v: u32, len: u32) -> Self {
+        v as Self / len.saturating_sub(1).max(1) as Self
+    }
+
+    fn wrap(self) -> u8 {
+        (self * 255.0).round() as i64 as u8
+    }
+
+    fn saturate(self) -> u8 {
+        (self * 255.0).round().clamp(0.0, 255.0) as u8
+    }
+
+    fn offset(self) -> i64 {
+        self.round() as i64
+    }
+
+    fn add(self, rhs: Self) -> Self {
+        self + rhs
+    }
+
+    fn sub(self, rhs: Self) -> Self {
+        self - rhs
+    }
+
+    fn mul(self, rhs: Self) -> Self {
+        self * rhs
+    }
+
+    fn div(self, rhs: Self) -> Self {
+        if rhs == 0.0 {
+            return self;
+        }
+        self / rhs
+    }
+
+    fn rem(self, rhs: Self) -> Self {
+        if rhs == 0.0 {
+            return self;
+        }
+        self.rem_euclid(rhs)
+    }
+
+    fn pow(self, rhs: Self) -> Self {
+        self.powf(rhs)
+    }
+
+    fn shl(self, rhs: Self) -> Self {
+        Self::from_byte(self.saturate().wrapping_shl(rhs.max(0.0) as u32))
+    }
+
+    fn shr(self, rhs: Self) -> Self {
+        Self::from_byte(self.saturate().wrapping_shr(rhs.max(0.0) as u32))
+    }
+
+    fn bitand(self, rhs: Self) -> Self {
+        Self::from_byte(self.saturate() & rhs.saturate())
+    }
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self::from_byte(self.saturate() | rhs.saturate())
+    }
+
+    fn bitxor(self, rhs: Self) -> Self {
+        Self::from_byte(self.saturate() ^ rhs.saturate())
+    }
+
+    fn and_not(self, rhs: Self) -> Self {
+        Self::from_byte(self.saturate() & !rhs.saturate())
+    }
+
+    fn weight(self, rhs: Self) -> Self {
+        self * rhs
+    }
+
+    fn not(self) -> Self {
+        1.0 - self
+    }
+
+    fn neg(self) -> Self {
+        -self
+    }
+
+    fn abs(self) -> Self {
+        self.abs()
+    }
+
+    fn mix(self, other: Self, t: Self) -> Self {
+        (other - self).mul_add(t, self)
+    }
+
+    fn mean(values: impl IntoIterator<Item = Self>) -> Self {
+        let mut count = 0;
+        let sum: Self = values.into_iter().inspect(|_| count += 1).sum();
+        sum / count as Self
+    }
+
+    fn to_unit(self) -> f64 {
+        f64::from(self)
+    }
+
+    fn from_unit(v: f64) -> Self {
+        v as Self
+    }
+
+    fn to_signed_unit(self) -> f64 {
+        f64::from(self)
+    }
+
+    fn from_turn(t: f64) -> Self {
+        t.rem_euclid(1.0) as Self
+    }
+
+    fn to_turn(self) -> f64 {
+        f64::from(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_byte_wraps() {
+        assert_eq!(Lane::add(200u8, 100), 44);
+        assert_eq!(Lane::sub(10u8, 20), 246);
+        assert_eq!(Lane::div(10u8, 0), 10);
+        assert_eq!(Lane::shl(1u8, 9), 2);
+    }
+
+    #[test]
+    fn test_wide_writes() {
+        let v = Lane::add(200i32, 100);
+        assert_eq!(v, 300);
+        assert_eq!(v.wrap(), 44);
+        assert_eq!(v.saturate(), 255);
+        assert_eq!(Lane::sub(10i32, 20).saturate(), 0);
+        assert_eq!(Lane::not(10i32), 245);
+        assert_eq!(Lane::shl(1i32, 40), 0);
+    }
+
+    #[test]
+    fn test_float() {
+        assert_eq!(f32::from_byte(255), 1.0);
+        assert_eq!(Lane::add(0.75f32, 0.5).saturate(), 255);
+        assert_eq!(Lane::bitand(1.0f32, 0.5).saturate(), 128);
+        assert_eq!(f32::coord(3, 4), 1.0);
+        assert_eq!(u8::coord(3, 4), 191);
+    }
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!("Saturate".parse(), Ok(NumericMode::Saturate));
+        assert_eq!("float".parse(), Ok(NumericMode::Float));
+        assert!("double".parse::<NumericMode>().is_err());
+    }
+}
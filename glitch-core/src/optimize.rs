@@ -0,0 +1,548 @@
+use crate::ast::{generated_name, Ast, Expr, Statement};
+use crate::eval::{binary_op, call_func, unary_op};
+use crate::numeric::{Lane, NumericMode};
+use crate::token::{self, Pragma, Token};
+use std::collections::HashMap;
+
+/// Rewrites `tokens` into a shorter list that renders bit-identical output when
+/// run in `numeric` mode, or the mode the expression picks with `use <mode>;`.
+///
+/// - Sub-expressions without pixel operands are computed once with the
+///   arithmetic of the mode, e.g. `128 & (255 - 3)` becomes `128`.
+/// - Operations that leave their operand unchanged are dropped, e.g. `c ^ 0`
+///   or `c @ 255`.
+/// - Pure sub-expressions that appear more than once are computed once into a
+///   binding, and bindings that are never read are removed.
+///
+/// Operands that draw random numbers (`N`, `t`, `g` and `r`) are never dropped
+/// or shared, so every pixel draws the same numbers in the same order. Tokens
+/// that don't form an expression are returned unchanged.
+///
+/// # Example
+/// ```
+/// use glitch_core::{optimize, parser::shunting_yard, NumericMode, Token};
+///
+/// let tokens = shunting_yard("(c ^ 0) & (255 - 3)").unwrap();
+/// assert_eq!(
+///     optimize(&tokens, NumericMode::Byte),
+///     [Token::Char('c'), Token::Num(252), Token::BitAnd]
+/// );
+/// ```
+pub fn optimize(tokens: &[Token], numeric: NumericMode) -> Vec<Token> {
+    let numeric = token::pragmas(tokens).fold(numeric, |current, pragma| match pragma {
+        Pragma::Numeric(mode) => mode,
+        Pragma::Space(_) => current,
+    });
+    let Some(mut ast) = Ast::from_tokens(tokens) else {
+        return tokens.to_vec();
+    };
+
+    match numeric {
+        NumericMode::Byte => Folder::<u8>::new(numeric).fold_ast(&mut ast),
+        NumericMode::Wide | NumericMode::Saturate => Folder::<i32>::new(numeric).fold_ast(&mut ast),
+        NumericMode::Float => Folder::<f32>::new(numeric).fold_ast(&mut ast),
+    }
+    share_repeated(&mut ast);
+    drop_unused(&mut ast);
+    ast.to_tokens()
+}
+
+/// Lanes whose constants can be written back as a token.
+trait Literal: Lane {
+    /// The literal token that evaluates to exactly this value, if there is one.
+    fn literal(self) -> Option<Token>;
+}
+
+impl Literal for u8 {
+    fn literal(self) -> Option<Token> {
+        Some(Token::Num(self))
+    }
+}
+
+impl Literal for i32 {
+    fn literal(self) -> Option<Token> {
+        u8::try_from(self).ok().map(Token::Num)
+    }
+}
+
+impl Literal for f32 {
+    fn literal(self) -> Option<Token> {
+        let n = self as u8;
+        if Self::from_literal(n).to_bits() == self.to_bits() {
+            Some(Token::Num(n))
+        } else if self.is_sign_positive() && self <= 255.0 {
+            // Decimal literals are read back without rounding in the float dialect,
+            // but a leading `-` would read as `Neg` and past 255 needs `use float;`
+            Some(Token::Float(self.to_bits()))
+        } else {
+            None
+        }
+    }
+}
+
+/// Folds constants and identities bottom-up, following constant bindings.
+struct Folder<L> {
+    numeric: NumericMode,
+    /// Value of every binding slot that currently holds a constant.
+    bindings: Vec<Option<[L; 3]>>,
+}
+
+impl<L: Literal> Folder<L> {
+    const fn new(numeric: NumericMode) -> Self {
+        Self {
+            numeric,
+            bindings: Vec::new(),
+        }
+    }
+
+    fn fold_ast(&mut self, ast: &mut Ast) {
+        for statement in &mut ast.statements {
+            match statement {
+                Statement::Pragma(_) => {}
+                Statement::Bind { slot, value } => {
+                    let (folded, constant) = self.fold(value);
+                    *value = folded;
+                    let slot = *slot as usize;
+                    if self.bindings.len() <= slot {
+                        self.bindings.resize(slot + 1, None);
+                    }
+                    self.bindings[slot] = constant;
+                }
+                Statement::Output { value, .. } => *value = self.fold(value).0,
+            }
+        }
+        if let Some(result) = &mut ast.result {
+            *result = self.fold(result).0;
+        }
+    }
+
+    /// Returns the simplified node and its value if it is a constant.
+    fn fold(&self, expr: &Expr) -> (Expr, Option<[L; 3]>) {
+        let (folded, constant) = match expr {
+            Expr::Value(tok) => (expr.clone(), self.constant(tok)),
+            Expr::Prefix(op, operand) => {
+                let (operand, value) = self.fold(operand);
+                let value = value.zip(unary_op::<L>(op)).map(|(v, f)| v.map(f));
+                (Expr::Prefix(*op, Box::new(operand)), value)
+            }
+            Expr::Swizzle(channels, operand) => {
+                let (operand, value) = self.fold(operand);
+                if *channels == [0, 1, 2] {
+                    return (operand, value);
+                }
+                let value = value.map(|v| channels.map(|c| v[c as usize % 3]));
+                (Expr::Swizzle(*channels, Box::new(operand)), value)
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let (lhs, a) = self.fold(lhs);
+                let (rhs, b) = self.fold(rhs);
+                let identity = right_identity(op, self.numeric);
+                if is_literal(&rhs, identity) {
+                    return (lhs, a);
+                }
+                if commutes(op) && is_literal(&lhs, identity) {
+                    return (rhs, b);
+                }
+                let value = match (a, b, binary_op::<L>(op)) {
+                    (Some(a), Some(b), Some(f)) => Some([0, 1, 2].map(|i| f(a[i], b[i]))),
+                    _ => None,
+                };
+                (Expr::Binary(*op, Box::new(lhs), Box::new(rhs)), value)
+            }
+            Expr::Sample(src, dx, dy) => {
+                let dx = Box::new(self.fold(dx).0);
+                (Expr::Sample(*src, dx, Box::new(self.fold(dy).0)), None)
+            }
+            Expr::If(cond, then, otherwise) => {
+                let (cond, c) = self.fold(cond);
+                let (then, t) = self.fold(then);
+                let (otherwise, o) = self.fold(otherwise);
+                // A constant condition picks a branch, if the other one can be skipped
+                match (c, t, o) {
+                    (Some(c), Some(t), Some(o)) => {
+                        let value = [0, 1, 2].map(|i| if c[i].is_true() { t[i] } else { o[i] });
+                        (
+                            Expr::If(cond.into(), then.into(), otherwise.into()),
+                            Some(value),
+                        )
+                    }
+                    (Some(c), _, _) if c.iter().all(|v| v.is_true()) && is_pure(&otherwise) => {
+                        return (then, t)
+                    }
+                    (Some(c), _, _) if !c.iter().any(|v| v.is_true()) && is_pure(&then) => {
+                        return (otherwise, o)
+                    }
+                    _ => (Expr::If(cond.into(), then.into(), otherwise.into()), None),
+                }
+            }
+            Expr::Call(func, args) => {
+                let (args, values): (Vec<Expr>, Vec<Option<[L; 3]>>) =
+                    args.iter().map(|arg| self.fold(arg)).unzip();
+                let value = if func.accepts(args.len() as u8) {
+                    values
+                        .into_iter()
+                        .collect::<Option<Vec<_>>>()
+                        .map(|values| call_func(*func, &values))
+                } else {
+                    None
+                };
+                (Expr::Call(*func, args), value)
+            }
+        };
+
+        // Constants the same in every channel become a single literal
+        let literal = constant.and_then(|value| {
+            let [r, g, b] = value.map(L::literal);
+            r.filter(|r| Some(*r) == g && Some(*r) == b)
+        });
+        (literal.map_or(folded, Expr::Value), constant)
+    }
+
+    /// Value of a constant operand, as evaluation would push it.
+    fn constant(&self, tok: &Token) -> Option<[L; 3]> {
+        match *tok {
+            Token::Num(n) => Some([L::from_literal(n); 3]),
+            Token::Float(bits) => Some([L::from_decimal(f32::from_bits(bits)); 3]),
+            Token::RGBColor((part, n)) => {
+                let mut value = [L::from_byte(0); 3];
+                value[match part {
+                    'R' => 0,
+                    'G' => 1,
+                    'B' => 2,
+                    _ => return None,
+                }] = L::from_byte(n);
+                Some(value)
+            }
+            Token::Load(slot) => self.bindings.get(slot as usize).copied().flatten(),
+            _ => None,
+        }
+    }
+}
+
+/// The literal `k` for which `x op k` is exactly `x` for every value of the
+/// mode, e.g. `0` for `^`.
+const fn right_identity(op: &Token, numeric: NumericMode) -> Option<u8> {
+    // `-0.0 + 0.0` is `0.0`, and the float dialect runs bitwise operators on bytes
+    let float = matches!(numeric, NumericMode::Float);
+    match op {
+        Token::Add
+        | Token::BitOr
+        | Token::BitXor
+        | Token::BitAndNot
+        | Token::BitLShift
+        | Token::BitRShift
+            if !float =>
+        {
+            Some(0)
+        }
+        Token::Sub => Some(0),
+        Token::Mul | Token::Div => Some(1),
+        Token::Pow if !float => Some(1),
+        // Wide values have bits above the byte
+        Token::BitAnd if matches!(numeric, NumericMode::Byte) => Some(255),
+        Token::Weight if float => Some(1),
+        Token::Weight => Some(255),
+        _ => None,
+    }
+}
+
+/// Operators whose identity works on the left as well, e.g. `0 ^ x`.
+const fn commutes(op: &Token) -> bool {
+    matches!(
+        op,
+        Token::Add | Token::Mul | Token::BitOr | Token::BitXor | Token::BitAnd
+    )
+}
+
+fn is_literal(expr: &Expr, n: Option<u8>) -> bool {
+    n.is_some_and(|n| *expr == Expr::Value(Token::Num(n)))
+}
+
+/// Operands that draw from the random number generator.
+const fn draws_random(tok: &Token) -> bool {
+    matches!(tok, Token::Char('N' | 't' | 'g') | Token::Random(_))
+}
+
+/// Whether `expr` can be skipped or computed once for several uses without
+/// changing the random numbers drawn for the pixel.
+fn is_pure(expr: &Expr) -> bool {
+    !expr.any(&|node| matches!(node, Expr::Value(tok) if draws_random(tok)))
+}
+
+/// Values of the statements and the result, numbered by statement.
+fn values(ast: &Ast) -> impl Iterator<Item = (usize, &Expr)> {
+    ast.statements
+        .iter()
+        .map(Statement::value)
+        .chain([ast.result.as_ref()])
+        .enumerate()
+        .filter_map(|(i, value)| value.map(|value| (i, value)))
+}
+
+/// Moves pure sub-expressions that appear more than once into a binding ahead
+/// of their first use, largest saving first.
+fn share_repeated(ast: &mut Ast) {
+    // Loads of slots that are assigned more than once can mean different values
+    let mut stores: HashMap<u8, usize> = HashMap::new();
+    for statement in &ast.statements {
+        if let Statement::Bind { slot, .. } = statement {
+            *stores.entry(*slot).or_default() += 1;
+        }
+    }
+    let reassigned: Vec<u8> = stores
+        .into_iter()
+        .filter_map(|(slot, count)| (count > 1).then_some(slot))
+        .collect();
+
+    while let Some((shared, first)) = most_repeated(ast, &reassigned) {
+        let Ok(slot) = u8::try_from(ast.names.len()) else {
+            return;
+        };
+        let load = Expr::Value(Token::Load(slot));
+        for value in ast
+            .statements
+            .iter_mut()
+            .filter_map(Statement::value_mut)
+            .chain(ast.result.as_mut())
+        {
+            replace(value, &shared, &load);
+        }
+        ast.statements.insert(
+            first,
+            Statement::Bind {
+                slot,
+                value: shared,
+            },
+        );
+        ast.names.push(generated_name(slot as usize));
+    }
+}
+
+/// The repeated sub-expression that saves the most tokens when shared, with the
+/// statement it first appears in.
+fn most_repeated(ast: &Ast, reassigned: &[u8]) -> Option<(Expr, usize)> {
+    // Candidates in order of first appearance, so ties always pick the same one
+    let mut index: HashMap<&Expr, usize> = HashMap::new();
+    let mut candidates: Vec<(&Expr, usize, usize)> = Vec::new();
+
+    fn visit<'a>(
+        expr: &'a Expr,
+        statement: usize,
+        reassigned: &[u8],
+        index: &mut HashMap<&'a Expr, usize>,
+        candidates: &mut Vec<(&'a Expr, usize, usize)>,
+    ) -> bool {
+        let mut shareable = true;
+        for child in expr.children() {
+            shareable &= visit(child, statement, reassigned, index, candidates);
+        }
+        match expr {
+            Expr::Value(Token::Load(slot)) => !reassigned.contains(slot),
+            Expr::Value(tok) => !draws_random(tok),
+            _ if shareable => {
+                match index.get(expr) {
+                    Some(&i) => candidates[i].1 += 1,
+                    None => {
+                        index.insert(expr, candidates.len());
+                        candidates.push((expr, 1, statement));
+                    }
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    for (statement, value) in values(ast) {
+        visit(value, statement, reassigned, &mut index, &mut candidates);
+    }
+
+    candidates
+        .into_iter()
+        .filter_map(|(expr, count, first)| {
+            // `count` copies become one copy, a store and `count` loads
+            let size = expr.token_count();
+            let saved = (count * size).checked_sub(size + 1 + count)?;
+            (saved > 0).then_some((saved, size, expr, first))
+        })
+        .max_by_key(|&(saved, size, ..)| (saved, size))
+        .map(|(.., expr, first)| (expr.clone(), first))
+}
+
+fn replace(expr: &mut Expr, target: &Expr, with: &Expr) {
+    if expr == target {
+        *expr = with.clone();
+    } else {
+        for child in expr.children_mut() {
+            replace(child, target, with);
+        }
+    }
+}
+
+/// Removes pure bindings that are never read.
+fn drop_unused(ast: &mut Ast) {
+    while let Some(unused) = (0..ast.statements.len()).find(|&i| match &ast.statements[i] {
+        Statement::Bind { slot, value } => is_pure(value) && !read_after(ast, i, *slot),
+        _ => false,
+    }) {
+        ast.statements.remove(unused);
+    }
+}
+
+/// Whether the value stored in `slot` by statement `i` is loaded before it is
+/// assigned again.
+fn read_after(ast: &Ast, i: usize, slot: u8) -> bool {
+    let load = Expr::Value(Token::Load(slot));
+    for (j, value) in values(ast).skip_while(|&(j, _)| j <= i) {
+        if value.any(&|node| *node == load) {
+            return true;
+        }
+        if matches!(ast.statements.get(j), Some(Statement::Bind { slot: s, .. }) if *s == slot) {
+            return false;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::shunting_yard;
+    use crate::Pipeline;
+    use image::{DynamicImage, Rgba, RgbaImage};
+
+    fn optimized(source: &str, numeric: NumericMode) -> Vec<Token> {
+        optimize(&shunting_yard(source).unwrap(), numeric)
+    }
+
+    #[test]
+    fn test_folds_constants() {
+        assert_eq!(
+            optimized("128 & (255 - 3)", NumericMode::Byte),
+            [Token::Num(128)]
+        );
+        // 3 - 5 wraps to 254 in bytes, but is -2 in wide mode
+        assert_eq!(
+            optimized("c + (3 - 5) / 2", NumericMode::Byte),
+            shunting_yard("c + 127").unwrap()
+        );
+        assert_eq!(
+            optimized("c + (3 - 5) / 2", NumericMode::Wide),
+            shunting_yard("c + (3 - 5) / 2").unwrap()
+        );
+        assert_eq!(
+            optimized("use float; c * (0.5 + 2)", NumericMode::Byte),
+            shunting_yard("use float; c * 2.5").unwrap()
+        );
+        assert_eq!(
+            optimized("n = 3 * 4; c ^ if(n gt 10, n, h)", NumericMode::Byte),
+            shunting_yard("c ^ 12").unwrap()
+        );
+        // Colors and swizzles fold channel by channel
+        assert_eq!(
+            optimized("(R10 + G10).rrr", NumericMode::Byte),
+            [Token::Num(10)]
+        );
+        assert_eq!(
+            optimized("min(40, 20 + 30, 90) + 1", NumericMode::Byte),
+            [Token::Num(41)]
+        );
+    }
+
+    #[test]
+    fn test_drops_identities() {
+        let c = [Token::Char('c')];
+        assert_eq!(optimized("c ^ 0", NumericMode::Byte), c);
+        assert_eq!(optimized("c @ 255", NumericMode::Byte), c);
+        assert_eq!(optimized("(0 | c.rgb) * 1", NumericMode::Wide), c);
+        assert_eq!(optimized("c - 0.0", NumericMode::Float), c);
+        // Not identities in these modes
+        assert_eq!(optimized("c & 255", NumericMode::Wide).len(), 3);
+        assert_eq!(optimized("c + 0", NumericMode::Float).len(), 3);
+        assert_eq!(optimized("255 @ c", NumericMode::Byte).len(), 3);
+    }
+
+    #[test]
+    fn test_shares_repeated() {
+        let tokens = optimized("(c ^ h) * 3 + (c ^ h) * 3", NumericMode::Byte);
+        assert_eq!(tokens, shunting_yard("_a = (c ^ h) * 3; _a + _a").unwrap());
+
+        // Unused bindings go, unless they draw random numbers
+        assert_eq!(
+            optimized("a = c + h; c", NumericMode::Byte),
+            [Token::Char('c')]
+        );
+        assert_eq!(optimized("a = N; c", NumericMode::Byte).len(), 3);
+
+        // Random operands give a different value every time they are drawn
+        let random = "(N + c) & (N + c) & (N + c)";
+        assert_eq!(
+            optimized(random, NumericMode::Byte),
+            shunting_yard(random).unwrap()
+        );
+        // and can't be skipped by a constant condition
+        assert_eq!(optimized("if(1, c, N)", NumericMode::Byte).len(), 4);
+    }
+
+    #[test]
+    fn test_bit_identical() {
+        let img: DynamicImage = RgbaImage::from_fn(9, 7, |x, y| {
+            Rgba([
+                x as u8 * 29,
+                y as u8 * 37,
+                (x * y * 11) as u8,
+                255 - x as u8,
+            ])
+        })
+        .into();
+
+        let sources = include_str!("../../tests/line_art_mirrored.txt")
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .chain([
+                "128 & (255 - 3) | c ^ 0",
+                "a = c ^ h; n = 7 * 3; (a + n) - (a + n) * 2 + (a + n) @ 255",
+                "(c - 3 - 5) / 2 + (250 + 10) # 1 - 0 * 1",
+                "r: (c + 1) * 2; g: (c + 1) * 2 - s; b: abs(c - h) + abs(c - h) + N;",
+                "if(x gt 100, c[1, -1], h) + if(0, t, (c - 2) & 255)",
+                "use ycbcr; hsv(c.r + 0, 0.25, 0.5) ^ -(-c) + ~~c",
+                "min(200 + 100, c, 7 / 0) + g + r3 + (N + c) * (N + c)",
+                "c * (200 + 100) + c * (0 - 0.5)",
+            ]);
+
+        for source in sources {
+            let tokens = shunting_yard(source).unwrap();
+            for numeric in [
+                NumericMode::Byte,
+                NumericMode::Wide,
+                NumericMode::Saturate,
+                NumericMode::Float,
+            ] {
+                let render = |tokens: Vec<Token>| {
+                    let pipeline = Pipeline {
+                        expressions: vec![tokens],
+                        seed: 7,
+                        numeric,
+                        ..Default::default()
+                    };
+                    pipeline.render(img.clone()).unwrap()
+                };
+                let optimized = optimize(&tokens, numeric);
+                assert!(optimized.len() <= tokens.len(), "{source}");
+                // The optimized expression reads back without gaining tokens
+                let text = Ast::from_tokens(&optimized).unwrap().to_string();
+                assert_eq!(
+                    shunting_yard(&text).map(|read| read.len()),
+                    Ok(optimized.len()),
+                    "{text}"
+                );
+                assert_eq!(
+                    render(optimized),
+                    render(tokens.clone()),
+                    "{source} in {} mode",
+                    numeric.name()
+                );
+            }
+        }
+    }
+}
@@ -2,15 +2,40 @@
 use std::collections::VecDeque;
 use std::iter::Peekable;
 use std::str::Chars;
-use crate::token::Token;
+use crate::error::{GlitchError, Span};
+use crate::numeric::NumericMode;
+use crate::token::{self, Func, Param, Pragma, Token};
 
-fn parse_value(value_str: &str, default: u8, current_position: usize) -> Result<u8, String> {
+/// A token together with the part of the source it was read from.
+pub type Spanned = (Token, Span);
+
+/// Maps character positions, as counted while parsing, to byte offsets.
+struct Offsets(Vec<usize>);
+
+impl Offsets {
+    fn new(input: &str) -> Self {
+        Self(
+            input
+                .char_indices()
+                .map(|(i, _)| i)
+                .chain([input.len()])
+                .collect(),
+        )
+    }
+
+    /// Byte span of the characters `start..end`.
+    fn span(&self, start: usize, end: usize) -> Span {
+        self.0[start]..self.0[end]
+    }
+}
+
+fn parse_value(value_str: &str, default: u8, min: u8, span: Span) -> Result<u8, GlitchError> {
     if value_str.is_empty() {
-        Ok(default)
-    } else {
-        value_str
-            .parse::<u8>()
-            .map_err(|_| format!("Invalid value specified at position {}", current_position))
+        return Ok(default);
+    }
+    match value_str.parse::<u8>() {
+        Ok(value) if value >= min => Ok(value),
+        _ => Err(GlitchError::NumberOutOfRange { min, span }),
     }
 }
 
@@ -27,122 +52,711 @@ fn read_digits(chars_iter: &mut Peekable<Chars<'_>>, current_position: &mut usiz
     range_str
 }
 
-pub fn shunting_yard(input: &str) -> Result<Vec<Token>, String> {
-    let mut output_queue: VecDeque<Token> = VecDeque::new();
-    let mut operator_stack: Vec<Token> = Vec::new();
-    let mut number_buffer: Option<u8> = None;
+fn read_word(
+    first: char,
+    chars_iter: &mut Peekable<Chars<'_>>,
+    current_position: &mut usize,
+) -> String {
+    let mut word = String::from(first);
+    while let Some(&next_char) = chars_iter.peek() {
+        if is_word_char(next_char) {
+            word.push(chars_iter.next().unwrap());
+            *current_position += 1;
+        } else {
+            break;
+        }
+    }
+    word
+}
+
+/// Returns true if the next non-whitespace character is a lone `=` (a binding).
+fn next_is_assign(chars_iter: &Peekable<Chars<'_>>) -> bool {
+    let mut look = chars_iter.clone();
+    while look.next_if(|c| c.is_whitespace()).is_some() {}
+    look.next() == Some('=') && look.peek() != Some(&'=')
+}
+
+/// Returns true if the next non-whitespace character is a `:` (a channel label).
+fn next_is_label(chars_iter: &Peekable<Chars<'_>>) -> bool {
+    let mut look = chars_iter.clone();
+    while look.next_if(|c| c.is_whitespace()).is_some() {}
+    look.next() == Some(':')
+}
+
+/// Returns true if the next non-whitespace character is a `[` (a sample offset).
+fn next_is_offset(chars_iter: &Peekable<Chars<'_>>) -> bool {
+    let mut look = chars_iter.clone();
+    while look.next_if(|c| c.is_whitespace()).is_some() {}
+    look.next() == Some('[')
+}
+
+/// Consumes whitespace and the `=` of a binding or `:` of a channel label.
+fn consume_assign(chars_iter: &mut Peekable<Chars<'_>>, current_position: &mut usize) {
+    while chars_iter.next_if(|c| c.is_whitespace()).is_some() {
+        *current_position += 1;
+    }
+    if chars_iter.next_if(|c| matches!(c, '=' | ':')).is_some() {
+        *current_position += 1;
+    }
+}
+
+/// Where the value of a statement goes.
+enum Target {
+    Binding(String),
+    Channel(u8),
+}
+
+/// Emits the token that stores a finished statement's value, if it has a target.
+/// `span` is where the target was named.
+fn finish_statement(
+    target: Option<(Target, Span)>,
+    bindings: &mut Vec<String>,
+    output_queue: &mut VecDeque<Spanned>,
+) -> Result<(), GlitchError> {
+    match target {
+        Some((Target::Binding(name), span)) => {
+            let slot = bind(bindings, name, span.clone())?;
+            output_queue.push_back((Token::Store(slot), span));
+        }
+        Some((Target::Channel(channel), span)) => {
+            output_queue.push_back((Token::Output(channel), span))
+        }
+        None => {}
+    }
+    Ok(())
+}
+
+/// Reads the rest of a number literal starting with `first`, including a
+/// fraction such as the `.25` of `0.25`.
+fn read_number(
+    first: char,
+    chars_iter: &mut Peekable<Chars<'_>>,
+    current_position: &mut usize,
+) -> String {
+    let mut literal = first.to_string();
+    literal.push_str(&read_digits(chars_iter, current_position));
+    // A `.` not followed by a digit starts a swizzle, as in `5.r`
+    let mut ahead = chars_iter.clone();
+    if ahead.next_if_eq(&'.').is_some() && ahead.peek().is_some_and(char::is_ascii_digit) {
+        chars_iter.next();
+        *current_position += 1;
+        literal.push('.');
+        literal.push_str(&read_digits(chars_iter, current_position));
+    }
+    literal
+}
+
+/// Reads the rest of a `use <name>;` statement, up to and including the `;`.
+fn parse_pragma(
+    chars_iter: &mut Peekable<Chars<'_>>,
+    current_position: &mut usize,
+    offsets: &Offsets,
+) -> Result<Pragma, GlitchError> {
+    while chars_iter.next_if(|c| c.is_whitespace()).is_some() {
+        *current_position += 1;
+    }
+    let start = *current_position;
+    let name = match chars_iter.next_if(|&c| is_word_char(c)) {
+        Some(first) => {
+            *current_position += 1;
+            read_word(first, chars_iter, current_position)
+        }
+        None => {
+            return Err(GlitchError::syntax(
+                "Expected a name after 'use'",
+                offsets.span(start, start),
+            ))
+        }
+    };
+    let pragma = if let Ok(space) = name.parse() {
+        Pragma::Space(space)
+    } else if let Ok(mode) = name.parse() {
+        Pragma::Numeric(mode)
+    } else {
+        return Err(GlitchError::syntax(
+            format!(
+                "Unknown setting '{}' (expected a colour space: rgb, hsv, ycbcr, lab; \
+                 or a numeric mode: byte, wide, saturate, float)",
+                name
+            ),
+            offsets.span(start, *current_position),
+        ));
+    };
+
+    while chars_iter.next_if(|c| c.is_whitespace()).is_some() {
+        *current_position += 1;
+    }
+    match chars_iter.next() {
+        Some(';') | None => {
+            *current_position += 1;
+            Ok(pragma)
+        }
+        Some(c) => Err(GlitchError::syntax(
+            format!("Expected ';' after 'use {}' but found '{}'", name, c),
+            offsets.span(*current_position, *current_position + 1),
+        )),
+    }
+}
+
+/// Consumes whitespace and the `(` that must follow a function name, returning
+/// the position of the `(`.
+fn consume_call_paren(
+    chars_iter: &mut Peekable<Chars<'_>>,
+    current_position: &mut usize,
+) -> Option<usize> {
+    while chars_iter.next_if(|c| c.is_whitespace()).is_some() {
+        *current_position += 1;
+    }
+    chars_iter.next_if_eq(&'(')?;
+    *current_position += 1;
+    Some(*current_position - 1)
+}
+
+/// Pops operators into the output queue until the innermost `(`, leaving it on the stack.
+fn pop_until_paren(operator_stack: &mut Vec<Spanned>, output_queue: &mut VecDeque<Spanned>) {
+    while let Some((op, _)) = operator_stack.last() {
+        if matches!(op, Token::LeftParen) {
+            break;
+        }
+        output_queue.push_back(operator_stack.pop().unwrap());
+    }
+}
+
+/// Pops every pending operator into the output queue at the end of a statement,
+/// reporting every paren left open.
+fn flush_operators(
+    operator_stack: &mut Vec<Spanned>,
+    output_queue: &mut VecDeque<Spanned>,
+    errors: &mut Vec<GlitchError>,
+) {
+    while let Some((op, span)) = operator_stack.pop() {
+        if matches!(op, Token::LeftParen) {
+            errors.push(GlitchError::MismatchedParen { span });
+        } else {
+            output_queue.push_back((op, span));
+        }
+    }
+}
+
+/// Assigns `name` a slot, reusing the existing slot when a binding is redefined.
+fn bind(bindings: &mut Vec<String>, name: String, span: Span) -> Result<u8, GlitchError> {
+    if let Some(slot) = bindings.iter().position(|b| *b == name) {
+        return Ok(slot as u8);
+    }
+    if bindings.len() > u8::MAX as usize {
+        return Err(GlitchError::syntax(
+            format!("Too many bindings (max {})", u8::MAX as usize + 1),
+            span,
+        ));
+    }
+    bindings.push(name);
+    Ok((bindings.len() - 1) as u8)
+}
+
+/// An open `(`, or the `[` of an offset sample.
+struct OpenParen {
+    /// The call, `if` or sample it belongs to and its argument count so far, or
+    /// `None` for a plain grouping paren.
+    call: Option<(Token, usize)>,
+    /// Length of the output queue when it was opened, to spot empty parens.
+    output_len: usize,
+}
+
+/// Parses an expression into RPN tokens.
+///
+/// # Errors
+/// Returns every syntax error found, in source order, each with the span it was found at.
+pub fn shunting_yard(input: &str) -> Result<Vec<Token>, Vec<GlitchError>> {
+    Ok(shunting_yard_spanned(input)?
+        .into_iter()
+        .map(|(token, _)| token)
+        .collect())
+}
+
+/// Parses an expression into RPN tokens, each with the span of source it came from.
+///
+/// Operators point at their symbol, calls at the function name, and `Store` and
+/// `Output` at the binding or channel they assign.
+///
+/// # Errors
+/// Returns every syntax error found, in source order, each with the span it was found at.
+/// Parsing carries on after an error, so one mistake rarely causes more than one error.
+pub fn shunting_yard_spanned(input: &str) -> Result<Vec<Spanned>, Vec<GlitchError>> {
+    let offsets = Offsets::new(input);
+    let mut output_queue: VecDeque<Spanned> = VecDeque::new();
+    let mut operator_stack: Vec<Spanned> = Vec::new();
     let mut current_position: usize = 0;
+    let mut errors: Vec<GlitchError> = Vec::new();
+    // Set by `use float;`, which lifts the `0..=255` limit on literals.
+    let mut float_literals = false;
+
+    // Binding names, indexed by slot.
+    let mut bindings: Vec<String> = Vec::new();
+    let mut pending_target: Option<(Target, Span)> = None;
+    let mut statement_start = true;
+    // Output channels already given their own statement (`r:`, `g:`, `b:`, `a:`).
+    let mut assigned_channels = [false; 4];
+    // Whether the next token should be an operand, used to tell the
+    // `min(a, b)` call apart from the infix `a min b`, and to catch missing
+    // operands and operators.
+    let mut expect_operand = true;
 
-    let push_number_buffer = |number_buffer: &mut Option<u8>,
-                              output_queue: &mut VecDeque<Token>,
-                              _position: usize|
-     -> Result<(), String> {
-        if let Some(number) = *number_buffer {
-            output_queue.push_back(Token::Num(number));
-            *number_buffer = None;
+    // One entry per open paren.
+    let mut open_parens: Vec<OpenParen> = Vec::new();
+
+    // Reports an operand that directly follows another one, e.g. `c h`.
+    let start_operand = |expect_operand: &mut bool, errors: &mut Vec<GlitchError>, span: Span| {
+        if !*expect_operand {
+            errors.push(GlitchError::MissingOperator { span });
         }
-        Ok(())
+        *expect_operand = false;
     };
 
     let mut chars_iter = input.chars().peekable();
     while let Some(c) = chars_iter.next() {
         current_position += 1; // Update position for each character
+        let at = current_position - 1;
+        let here = offsets.span(at, current_position);
         match c {
             '0'..='9' => {
-                let digit = c.to_digit(10).unwrap() as i64;
-                number_buffer = match number_buffer {
-                    Some(number) => {
-                        let new_number = number as i64 * 10i64 + digit;
-                        if new_number > 255 {
-                            return Err(format!(
-                                "Number exceeds 255 at position {}",
-                                current_position
+                start_operand(&mut expect_operand, &mut errors, here);
+                let literal = read_number(c, &mut chars_iter, &mut current_position);
+                let span = offsets.span(at, current_position);
+                match literal.parse::<u8>() {
+                    Ok(n) => output_queue.push_back((Token::Num(n), span)),
+                    Err(_) => match literal.parse::<f32>() {
+                        // Only the float dialect has room for numbers past a byte
+                        Ok(value) if float_literals || value <= 255.0 => {
+                            output_queue.push_back((Token::Float(value.to_bits()), span));
+                        }
+                        _ => errors.push(GlitchError::NumberOutOfRange { min: 0, span }),
+                    },
+                }
+            }
+            c if is_word_char(c) => {
+                let word = read_word(c, &mut chars_iter, &mut current_position);
+                let word_span = offsets.span(at, current_position);
+
+                if statement_start && next_is_assign(&chars_iter) {
+                    if is_builtin(&word) {
+                        errors.push(GlitchError::syntax(
+                            format!("Cannot bind to built-in operand '{}'", word),
+                            word_span.clone(),
+                        ));
+                    }
+                    consume_assign(&mut chars_iter, &mut current_position);
+                    pending_target = Some((Target::Binding(word), word_span));
+                    statement_start = false;
+                    expect_operand = true;
+                    continue;
+                }
+
+                if word == "use" && statement_start {
+                    if !output_queue
+                        .iter()
+                        .all(|(t, _)| matches!(t, Token::Pragma(_)))
+                    {
+                        errors.push(GlitchError::syntax(
+                            "'use' must come before any other statement",
+                            word_span.clone(),
+                        ));
+                    }
+                    match parse_pragma(&mut chars_iter, &mut current_position, &offsets) {
+                        Ok(pragma) => {
+                            float_literals |= pragma == Pragma::Numeric(NumericMode::Float);
+                            let kind = std::mem::discriminant(&pragma);
+                            if output_queue.iter().any(|(t, _)| {
+                                matches!(t, Token::Pragma(p) if std::mem::discriminant(p) == kind)
+                            }) {
+                                errors.push(GlitchError::syntax(
+                                    "Setting chosen more than once by 'use'",
+                                    word_span.clone(),
+                                ));
+                            }
+                            output_queue.push_back((Token::Pragma(pragma), word_span));
+                        }
+                        Err(err) => errors.push(err),
+                    }
+                    expect_operand = true;
+                    continue;
+                }
+
+                if let Some(channel) = channel_index(&word).filter(|_| statement_start) {
+                    if next_is_label(&chars_iter) {
+                        if assigned_channels[channel as usize] {
+                            errors.push(GlitchError::syntax(
+                                format!("Channel '{}' is assigned more than once", word),
+                                word_span.clone(),
                             ));
-                        } else {
-                            Some(new_number as u8)
                         }
+                        assigned_channels[channel as usize] = true;
+                        consume_assign(&mut chars_iter, &mut current_position);
+                        pending_target = Some((Target::Channel(channel), word_span));
+                        statement_start = false;
+                        expect_operand = true;
+                        continue;
                     }
-                    None => Some(digit as u8),
-                };
-            }
-            'r' => {
-                push_number_buffer(&mut number_buffer, &mut output_queue, current_position)?;
+                }
 
-                let range_str = read_digits(&mut chars_iter, &mut current_position);
-                let range = parse_value(&range_str, 1, current_position)?;
-                if range == 0 {
-                    return Err("Range cannot be 0 just use 'c'".to_string());
+                // Infix words, unless a function of the same name is being called
+                let operator = word_to_operator(&word)
+                    .filter(|_| !(expect_operand && Func::from_name(&word).is_some()));
+                if let Some(token) = operator {
+                    if expect_operand {
+                        errors.push(GlitchError::MissingOperand { span: word_span });
+                    } else {
+                        handle_operator(&mut operator_stack, &mut output_queue, (token, word_span));
+                        expect_operand = true;
+                    }
+                    statement_start = false;
+                    continue;
                 }
 
-                output_queue.push_back(Token::Random(range));
-            }
-            'R' | 'G' | 'B' => {
-                push_number_buffer(&mut number_buffer, &mut output_queue, current_position)?;
-                let part = c;
+                // Calls need their `(`; without it the word is skipped
+                let call_paren = if word == "if" || Func::from_name(&word).is_some() {
+                    match consume_call_paren(&mut chars_iter, &mut current_position) {
+                        Some(paren) => Some(offsets.span(paren, paren + 1)),
+                        None => {
+                            errors.push(GlitchError::syntax(
+                                format!("Expected '(' after '{}'", word),
+                                word_span,
+                            ));
+                            statement_start = false;
+                            continue;
+                        }
+                    }
+                } else {
+                    None
+                };
 
-                let value_str = read_digits(&mut chars_iter, &mut current_position);
-                let value = parse_value(&value_str, 255, current_position)?;
-                output_queue.push_back(Token::RGBColor((part, value)));
-            }
-            'b' => {
-                push_number_buffer(&mut number_buffer, &mut output_queue, current_position)?;
+                start_operand(&mut expect_operand, &mut errors, word_span.clone());
+                // Operands with a numeric parameter, e.g. `r2` or `k3`
+                let mut read_parameter = |default: u8, min: u8| {
+                    let start = current_position;
+                    let value_str = read_digits(&mut chars_iter, &mut current_position);
+                    let span = offsets.span(start, current_position);
+                    let value = parse_value(&value_str, default, min, span).unwrap_or_else(|err| {
+                        errors.push(err);
+                        default
+                    });
+                    (value, offsets.span(at, current_position))
+                };
+                match word.as_str() {
+                    "r" => {
+                        let (range, span) = read_parameter(1, 1);
+                        output_queue.push_back((Token::Random(range), span));
+                    }
+                    "R" | "G" | "B" => {
+                        let part = c;
 
-                let value_str = read_digits(&mut chars_iter, &mut current_position);
-                let value = parse_value(&value_str, 255, current_position)?;
-                output_queue.push_back(Token::Brightness(value));
+                        let (value, span) = read_parameter(255, 0);
+                        output_queue.push_back((Token::RGBColor((part, value)), span));
+                    }
+                    "k" => {
+                        let (radius, span) = read_parameter(1, 1);
+                        output_queue.push_back((Token::Blur(radius), span));
+                    }
+                    "b" => {
+                        let (value, span) = read_parameter(255, 0);
+                        output_queue.push_back((Token::Brightness(value), span));
+                    }
+                    "i" => {
+                        output_queue.push_back((Token::Invert, word_span));
+                    }
+                    _ if call_paren.is_some() => {
+                        let call =
+                            Func::from_name(&word).map_or(Token::If, |func| Token::Call(func, 0));
+                        operator_stack.push((call, word_span));
+                        operator_stack.push((Token::LeftParen, call_paren.unwrap_or_default()));
+                        open_parens.push(OpenParen {
+                            call: Some((call, 1)),
+                            output_len: output_queue.len(),
+                        });
+                        expect_operand = true;
+                    }
+                    _ if Param::from_name(&word).is_some() => {
+                        if let Some(param) = Param::from_name(&word) {
+                            output_queue.push_back((Token::Param(param), word_span));
+                        }
+                    }
+                    _ if word.len() == 1 && is_sampleable(c) && next_is_offset(&chars_iter) => {
+                        while chars_iter.next_if(|c| *c != '[').is_some() {
+                            current_position += 1;
+                        }
+                        chars_iter.next();
+                        current_position += 1;
+                        operator_stack.push((Token::Sample(c), word_span));
+                        operator_stack.push((
+                            Token::LeftParen,
+                            offsets.span(current_position - 1, current_position),
+                        ));
+                        open_parens.push(OpenParen {
+                            call: Some((Token::Sample(c), 1)),
+                            output_len: output_queue.len(),
+                        });
+                        expect_operand = true;
+                    }
+                    _ if word.len() == 1 && valid_tok(c) => {
+                        output_queue.push_back((Token::Char(c), word_span));
+                    }
+                    _ => match bindings.iter().position(|b| *b == word) {
+                        Some(slot) => output_queue.push_back((Token::Load(slot as u8), word_span)),
+                        None => errors.push(GlitchError::syntax(
+                            format!("Unknown identifier '{}'", word),
+                            word_span,
+                        )),
+                    },
+                }
             }
-            'i' => {
-                output_queue.push_back(Token::Invert);
+            '<' | '>' | '=' | '!' if chars_iter.peek() == Some(&'=') => {
+                chars_iter.next();
+                current_position += 1;
+                let token = match c {
+                    '<' => Token::LessEqual,
+                    '>' => Token::GreaterEqual,
+                    '=' => Token::Equal,
+                    _ => Token::NotEqual,
+                };
+                let span = offsets.span(at, current_position);
+                if expect_operand {
+                    errors.push(GlitchError::MissingOperand { span });
+                } else {
+                    handle_operator(&mut operator_stack, &mut output_queue, (token, span));
+                    expect_operand = true;
+                }
+            }
+            '-' | '~' if expect_operand => {
+                // Prefix operators bind tighter than any binary operator. They are pushed
+                // without popping since their operand hasn't been read yet.
+                operator_stack.push((if c == '-' { Token::Neg } else { Token::Not }, here));
             }
             c if char_to_token(c).is_some() => {
-                push_number_buffer(&mut number_buffer, &mut output_queue, current_position)?;
-                if let Some(token) = char_to_token(c) {
-                    handle_operator(&mut operator_stack, &mut output_queue, token);
+                if expect_operand {
+                    errors.push(GlitchError::MissingOperand { span: here });
+                } else if let Some(token) = char_to_token(c) {
+                    handle_operator(&mut operator_stack, &mut output_queue, (token, here));
+                    expect_operand = true;
+                }
+            }
+            '.' => {
+                let mut channels = String::new();
+                while let Some(ch) = chars_iter.next_if(|c| is_word_char(*c)) {
+                    channels.push(ch);
+                    current_position += 1;
+                }
+                let span = offsets.span(at, current_position);
+                if expect_operand {
+                    errors.push(GlitchError::syntax("Swizzle must follow a value", span));
+                } else {
+                    match parse_swizzle(&channels, span.clone()) {
+                        Ok(swizzle) => output_queue.push_back((Token::Swizzle(swizzle), span)),
+                        Err(err) => errors.push(err),
+                    }
                 }
             }
             '(' => {
-                push_number_buffer(&mut number_buffer, &mut output_queue, current_position)?;
-                operator_stack.push(Token::LeftParen);
+                start_operand(&mut expect_operand, &mut errors, here.clone());
+                operator_stack.push((Token::LeftParen, here));
+                open_parens.push(OpenParen {
+                    call: None,
+                    output_len: output_queue.len(),
+                });
+                expect_operand = true;
             }
-            ')' => {
-                push_number_buffer(&mut number_buffer, &mut output_queue, current_position)?;
-                while let Some(op) = operator_stack.pop() {
+            ')' | ']' => {
+                let Some(open) = open_parens.pop() else {
+                    errors.push(GlitchError::MismatchedParen { span: here });
+                    continue;
+                };
+                let empty = expect_operand
+                    && output_queue.len() == open.output_len
+                    && matches!(operator_stack.last(), Some((Token::LeftParen, _)));
+                let mut paren = here.clone();
+                while let Some((op, span)) = operator_stack.pop() {
                     if matches!(op, Token::LeftParen) {
+                        paren = span;
                         break;
                     }
-                    output_queue.push_back(op);
+                    output_queue.push_back((op, span));
+                }
+                if expect_operand && !empty {
+                    errors.push(GlitchError::MissingOperand { span: here.clone() });
+                }
+
+                match (c, open.call) {
+                    (')', None) if empty => errors.push(GlitchError::EmptyParens {
+                        span: paren.start..here.end,
+                    }),
+                    (')', None) => {}
+                    (')', Some((Token::Sample(source), _))) => errors.push(GlitchError::syntax(
+                        format!("Expected ']' to close the offset of '{}'", source),
+                        here,
+                    )),
+                    (')', Some((call, args))) => {
+                        let (_, span) = operator_stack.pop().unwrap();
+                        let args = if empty { 0 } else { args };
+                        match call {
+                            // Arity is checked by `verify`, which knows the function's name.
+                            Token::Call(func, _) => match u8::try_from(args) {
+                                Ok(args) => output_queue.push_back((Token::Call(func, args), span)),
+                                Err(_) => errors.push(GlitchError::syntax(
+                                    format!("Too many arguments to '{}'", func.name()),
+                                    span,
+                                )),
+                            },
+                            _ if args != 3 => errors.push(GlitchError::Arity {
+                                name: "if".to_string(),
+                                expected: "3 arguments".to_string(),
+                                found: args,
+                                span,
+                            }),
+                            _ => output_queue.push_back((call, span)),
+                        }
+                    }
+                    (_, Some((Token::Sample(source), 2))) => {
+                        let (_, span) = operator_stack.pop().unwrap();
+                        output_queue.push_back((Token::Sample(source), span));
+                    }
+                    (_, Some((Token::Sample(source), args))) => {
+                        operator_stack.pop();
+                        errors.push(GlitchError::syntax(
+                            format!(
+                                "Sampling '{}' needs 2 offsets [dx, dy] but got {}",
+                                source,
+                                if empty { 0 } else { args }
+                            ),
+                            here,
+                        ));
+                    }
+                    _ => errors.push(GlitchError::MismatchedParen { span: here }),
+                }
+                expect_operand = false;
+            }
+            ',' => {
+                if expect_operand {
+                    errors.push(GlitchError::MissingOperand { span: here.clone() });
+                }
+                pop_until_paren(&mut operator_stack, &mut output_queue);
+                expect_operand = true;
+                match open_parens.last_mut() {
+                    Some(OpenParen {
+                        call: Some((_, args)),
+                        ..
+                    }) => *args += 1,
+                    _ => errors.push(GlitchError::syntax(
+                        "Unexpected ',' outside of a function call",
+                        here,
+                    )),
+                }
+            }
+            ';' => {
+                flush_operators(&mut operator_stack, &mut output_queue, &mut errors);
+                open_parens.clear();
+                if let Err(err) =
+                    finish_statement(pending_target.take(), &mut bindings, &mut output_queue)
+                {
+                    errors.push(err);
                 }
+                statement_start = true;
+                expect_operand = true;
+                continue;
             }
+            '=' => errors.push(GlitchError::syntax(
+                "Unexpected '=', bindings must start a statement",
+                here,
+            )),
             _ if c.is_whitespace() => {
-                push_number_buffer(&mut number_buffer, &mut output_queue, current_position)?;
+                continue;
             }
-            _ if valid_tok(c) => {
-                output_queue.push_back(Token::Char(c));
+            '[' => {
+                // Skip to the matching `]` so the offset's `,` and `]` aren't reported too
+                let mut depth = 1;
+                for ch in chars_iter.by_ref() {
+                    current_position += 1;
+                    depth += match ch {
+                        '[' => 1,
+                        ']' => -1,
+                        _ => 0,
+                    };
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                errors.push(GlitchError::syntax(
+                    "Only c, h, v, d, Y, A and p can be read at an offset",
+                    offsets.span(at, current_position),
+                ));
+                expect_operand = false;
             }
             _ => {
-                return Err(format!(
-                    "Invalid character '{}' at position {}",
-                    c, current_position
-                ))
+                // Read the character as an operator, so `3$5` is reported only once
+                errors.push(GlitchError::InvalidChar { ch: c, span: here });
+                expect_operand = true;
             }
         }
+        statement_start = false;
     }
 
-    push_number_buffer(&mut number_buffer, &mut output_queue, current_position)?;
+    flush_operators(&mut operator_stack, &mut output_queue, &mut errors);
+    // Before statements, `r : c` was `r` and-not `c`, so `r:`, `g:` and `b:` only
+    // label a channel in a statement that ends with `;`
+    if let Some((Target::Channel(channel @ 0..=2), span)) = &pending_target {
+        let label = token::channel_label(*channel);
+        errors.push(GlitchError::syntax(
+            format!(
+                "Channel statement '{0}:' must end with ';', write '({0}) :' for and-not",
+                label
+            ),
+            span.clone(),
+        ));
+    }
+    if let Err(err) = finish_statement(pending_target.take(), &mut bindings, &mut output_queue) {
+        errors.push(err);
+    }
 
-    while let Some(op) = operator_stack.pop() {
-        if matches!(op, Token::LeftParen) {
-            return Err("Mismatched parenthesis detected".to_string());
-        }
-        output_queue.push_back(op);
+    if errors.is_empty() {
+        Ok(output_queue.into())
+    } else {
+        errors.sort_by_key(|err| err.span().map(|span| span.start));
+        Err(errors)
     }
+}
 
-    Ok(output_queue.into())
+/// Parses the channels after a `.`: one channel is broadcast (`.g` is `.ggg`),
+/// three channels pick the new red, green and blue in order.
+fn parse_swizzle(channels: &str, span: Span) -> Result<[u8; 3], GlitchError> {
+    let mut picked = [0u8; 3];
+    let count = channels.chars().count();
+    for (i, ch) in channels.chars().enumerate().take(3) {
+        picked[i] = match ch {
+            'r' => 0,
+            'g' => 1,
+            'b' => 2,
+            _ => {
+                return Err(GlitchError::syntax(
+                    format!("Invalid swizzle channel '{}', expected r, g or b", ch),
+                    span,
+                ))
+            }
+        };
+    }
+    match count {
+        1 => Ok([picked[0]; 3]),
+        3 => Ok(picked),
+        _ => Err(GlitchError::syntax(
+            format!("Swizzle needs 1 or 3 channels but got {}", count),
+            span,
+        )),
+    }
 }
 
-fn handle_operator(operator_stack: &mut Vec<Token>, output_queue: &mut VecDeque<Token>, op: Token) {
-    while let Some(top_op) = operator_stack.last() {
-        if is_higher_precedence(&op, top_op) {
+fn handle_operator(
+    operator_stack: &mut Vec<Spanned>,
+    output_queue: &mut VecDeque<Spanned>,
+    op: Spanned,
+) {
+    while let Some((top_op, _)) = operator_stack.last() {
+        if is_higher_precedence(&op.0, top_op) {
             break;
         }
         output_queue.push_back(operator_stack.pop().unwrap());
@@ -156,7 +770,7 @@ const fn is_higher_precedence(new_op: &Token, top_op: &Token) -> bool {
     new_prec > top_prec
 }
 
-const fn operator_precedence(op: &Token) -> (i32, i32) {
+pub(crate) const fn operator_precedence(op: &Token) -> (i32, i32) {
     match op {
         Token::Add | Token::Sub | Token::BitOr | Token::BitXor => (4, 4),
         Token::Mul
@@ -167,7 +781,16 @@ const fn operator_precedence(op: &Token) -> (i32, i32) {
         | Token::BitLShift
         | Token::BitRShift
         | Token::Pow => (5, 5),
-        Token::Greater | Token::Weight => (6, 6),
+        Token::Greater
+        | Token::Less
+        | Token::GreaterEqual
+        | Token::LessEqual
+        | Token::Equal
+        | Token::NotEqual
+        | Token::Min
+        | Token::Max
+        | Token::Weight => (6, 6),
+        Token::Neg | Token::Not => (7, 7),
         _ => (-1, -1),
     }
 }
@@ -176,6 +799,8 @@ const fn valid_tok(tok: char) -> bool {
     matches!(
         tok,
         'c' | 's'
+            | 'p'
+            | 'A'
             | 'Y'
             | 'x'
             | 'y'
@@ -184,7 +809,6 @@ const fn valid_tok(tok: char) -> bool {
             | 'G'
             | 'B'*/
             | 'e'
-            | 'b'
             | 'H'
             | 'L'
             | 'h'
@@ -195,6 +819,33 @@ const fn valid_tok(tok: char) -> bool {
     )
 }
 
+const fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+/// Reserved words that can't be used as binding names.
+const KEYWORDS: &[&str] = &["if", "use", "lt", "le", "gt", "ge", "eq", "ne"];
+
+/// Words that name a built-in operand or keyword and can't be rebound.
+fn is_builtin(word: &str) -> bool {
+    let mut chars = word.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => {
+            valid_tok(c) || matches!(c, 'r' | 'R' | 'G' | 'B' | 'b' | 'k' | 'i' | 'T')
+        }
+        _ => {
+            KEYWORDS.contains(&word)
+                || Func::from_name(word).is_some()
+                || Param::from_name(word).is_some()
+        }
+    }
+}
+
+/// Operands that can be read at an offset with `src[dx, dy]`.
+pub(crate) const fn is_sampleable(c: char) -> bool {
+    matches!(c, 'c' | 'h' | 'v' | 'd' | 'Y' | 'A' | 'p')
+}
+
 const fn char_to_token(c: char) -> Option<Token> {
     match c {
         '+' => Some(Token::Add),
@@ -215,9 +866,37 @@ const fn char_to_token(c: char) -> Option<Token> {
     }
 }
 
+/// Output channel labelled by a per-channel statement, e.g. `g: h`.
+fn channel_index(word: &str) -> Option<u8> {
+    match word {
+        "r" => Some(0),
+        "g" => Some(1),
+        "b" => Some(2),
+        "a" => Some(3),
+        _ => None,
+    }
+}
+
+/// Infix operators spelled as words, e.g. `c lt 128`.
+fn word_to_operator(word: &str) -> Option<Token> {
+    match word {
+        "lt" => Some(Token::Less),
+        "le" => Some(Token::LessEqual),
+        "gt" => Some(Token::Greater),
+        "ge" => Some(Token::GreaterEqual),
+        "eq" => Some(Token::Equal),
+        "ne" => Some(Token::NotEqual),
+        "min" => Some(Token::Min),
+        "max" => Some(Token::Max),
+        _ => None,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::numeric::NumericMode;
+    use crate::space::ColorSpace;
 
     #[test]
     fn test_simple_expression() {
@@ -293,4 +972,498 @@ mod tests {
         ]);
         assert_eq!(shunting_yard(input), expected);
     }
+
+    #[test]
+    fn test_binding() {
+        let input = "a = c ^ 55; (a & s) + a";
+        let expected = Ok(vec![
+            Token::Char('c'),
+            Token::Num(55),
+            Token::BitXor,
+            Token::Store(0),
+            Token::Load(0),
+            Token::Char('s'),
+            Token::BitAnd,
+            Token::Load(0),
+            Token::Add,
+        ]);
+        assert_eq!(shunting_yard(input), expected);
+    }
+
+    #[test]
+    fn test_rebinding_reuses_slot() {
+        let input = "glow = c; glow = glow + 1; glow";
+        let expected = Ok(vec![
+            Token::Char('c'),
+            Token::Store(0),
+            Token::Load(0),
+            Token::Num(1),
+            Token::Add,
+            Token::Store(0),
+            Token::Load(0),
+        ]);
+        assert_eq!(shunting_yard(input), expected);
+    }
+
+    #[test]
+    fn test_binding_errors() {
+        // Unknown identifier
+        assert!(shunting_yard("a + c").is_err());
+        // Built-in operands can't be rebound
+        assert!(shunting_yard("c = 5; c").is_err());
+        // '=' only makes sense at the start of a statement
+        assert!(shunting_yard("c + a = 5").is_err());
+        // Binding is only visible after its statement
+        assert!(shunting_yard("a = a + 1; a").is_err());
+        // Unclosed paren inside a binding
+        assert!(shunting_yard("a = (c + 1; a").is_err());
+    }
+
+    #[test]
+    fn test_conditional() {
+        let input = "if(c ? 128, h, v + 1)";
+        let expected = Ok(vec![
+            Token::Char('c'),
+            Token::Num(128),
+            Token::Greater,
+            Token::Char('h'),
+            Token::Char('v'),
+            Token::Num(1),
+            Token::Add,
+            Token::If,
+        ]);
+        assert_eq!(shunting_yard(input), expected);
+    }
+
+    #[test]
+    fn test_conditional_errors() {
+        assert!(shunting_yard("if(c, h)").is_err());
+        assert!(shunting_yard("if c").is_err());
+        assert!(shunting_yard("(c, h)").is_err());
+        assert!(shunting_yard("if = c; h").is_err());
+    }
+
+    #[test]
+    fn test_comparison_operators() {
+        let input = "c lt 128 | h == v";
+        let expected = Ok(vec![
+            Token::Char('c'),
+            Token::Num(128),
+            Token::Less,
+            Token::Char('h'),
+            Token::Char('v'),
+            Token::Equal,
+            Token::BitOr,
+        ]);
+        assert_eq!(shunting_yard(input), expected);
+
+        assert_eq!(
+            shunting_yard("c<=h"),
+            Ok(vec![Token::Char('c'), Token::Char('h'), Token::LessEqual])
+        );
+        assert_eq!(
+            shunting_yard("c != h"),
+            Ok(vec![Token::Char('c'), Token::Char('h'), Token::NotEqual])
+        );
+        // A lone `<` is still a left shift
+        assert_eq!(
+            shunting_yard("c<2"),
+            Ok(vec![Token::Char('c'), Token::Num(2), Token::BitLShift])
+        );
+    }
+
+    #[test]
+    fn test_min_max() {
+        let input = "c min h max 32";
+        let expected = Ok(vec![
+            Token::Char('c'),
+            Token::Char('h'),
+            Token::Min,
+            Token::Num(32),
+            Token::Max,
+        ]);
+        assert_eq!(shunting_yard(input), expected);
+        assert!(shunting_yard("min = c; min").is_err());
+    }
+
+    #[test]
+    fn test_function_calls() {
+        let input = "clamp(abs(c - h), 16, 240)";
+        let expected = Ok(vec![
+            Token::Char('c'),
+            Token::Char('h'),
+            Token::Sub,
+            Token::Call(Func::Abs, 1),
+            Token::Num(16),
+            Token::Num(240),
+            Token::Call(Func::Clamp, 3),
+        ]);
+        assert_eq!(shunting_yard(input), expected);
+
+        // Variadic calls record how many arguments they were given
+        assert_eq!(
+            shunting_yard("avg(c, h, v)"),
+            Ok(vec![
+                Token::Char('c'),
+                Token::Char('h'),
+                Token::Char('v'),
+                Token::Call(Func::Avg, 3),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_min_max_call_or_infix() {
+        assert_eq!(
+            shunting_yard("max(c, h) min 200"),
+            Ok(vec![
+                Token::Char('c'),
+                Token::Char('h'),
+                Token::Call(Func::Max, 2),
+                Token::Num(200),
+                Token::Min,
+            ])
+        );
+        assert!(shunting_yard("sqrt c").is_err());
+        assert!(shunting_yard("sin = c; sin").is_err());
+    }
+
+    #[test]
+    fn test_swizzle() {
+        assert_eq!(
+            shunting_yard("h.g + c.gbr"),
+            Ok(vec![
+                Token::Char('h'),
+                Token::Swizzle([1, 1, 1]),
+                Token::Char('c'),
+                Token::Swizzle([1, 2, 0]),
+                Token::Add,
+            ])
+        );
+        // Applies to the whole group, not just its last operand
+        assert_eq!(
+            shunting_yard("(c ^ v).rrr"),
+            Ok(vec![
+                Token::Char('c'),
+                Token::Char('v'),
+                Token::BitXor,
+                Token::Swizzle([0, 0, 0]),
+            ])
+        );
+        assert!(shunting_yard("c.rg").is_err());
+        assert!(shunting_yard("c.x").is_err());
+        assert!(shunting_yard("c + .r").is_err());
+    }
+
+    #[test]
+    fn test_channel_statements() {
+        assert_eq!(
+            shunting_yard("r: c ^ x; g: h; b: s & 128;"),
+            Ok(vec![
+                Token::Char('c'),
+                Token::Char('x'),
+                Token::BitXor,
+                Token::Output(0),
+                Token::Char('h'),
+                Token::Output(1),
+                Token::Char('s'),
+                Token::Num(128),
+                Token::BitAnd,
+                Token::Output(2),
+            ])
+        );
+        // Without a label `r` and `b` are still the random and brightness operands
+        assert_eq!(
+            shunting_yard("b: r3; c"),
+            Ok(vec![Token::Random(3), Token::Output(2), Token::Char('c')])
+        );
+        assert!(shunting_yard("r: c; r: h").is_err());
+
+        // Without its `;` a label may be an old and-not expression, so it's rejected
+        assert_eq!(shunting_yard("g: h").unwrap_err()[0].span(), Some(0..1));
+        assert!(shunting_yard("r : c").is_err());
+        assert!(shunting_yard("a = c; r : a").is_err());
+        assert!(shunting_yard("r: c ^ x; g: h; b: s & 128").is_err());
+        assert_eq!(
+            shunting_yard("(g) : h"),
+            Ok(vec![Token::Char('g'), Token::Char('h'), Token::BitAndNot])
+        );
+        assert_eq!(
+            shunting_yard("a: A & 128"),
+            Ok(vec![
+                Token::Char('A'),
+                Token::Num(128),
+                Token::BitAnd,
+                Token::Output(3)
+            ])
+        );
+    }
+
+    #[test]
+    fn test_alpha() {
+        assert_eq!(
+            shunting_yard("a: A & 128; c"),
+            Ok(vec![
+                Token::Char('A'),
+                Token::Num(128),
+                Token::BitAnd,
+                Token::Output(3),
+                Token::Char('c'),
+            ])
+        );
+        // `a` is still free as a binding name
+        assert_eq!(
+            shunting_yard("a = c; a"),
+            Ok(vec![Token::Char('c'), Token::Store(0), Token::Load(0)])
+        );
+    }
+
+    #[test]
+    fn test_offset_sampling() {
+        assert_eq!(
+            shunting_yard("c[3, -2] ^ h[x / 4, 0]"),
+            Ok(vec![
+                Token::Num(3),
+                Token::Num(2),
+                Token::Neg,
+                Token::Sample('c'),
+                Token::Char('x'),
+                Token::Num(4),
+                Token::Div,
+                Token::Num(0),
+                Token::Sample('h'),
+                Token::BitXor,
+            ])
+        );
+        // Like a call, the offset may be written after a space
+        assert_eq!(shunting_yard("c [1, 2]"), shunting_yard("c[1, 2]"));
+        assert!(shunting_yard("c[1]").is_err());
+        assert!(shunting_yard("c[1, 2)").is_err());
+        assert!(shunting_yard("(c + 1]").is_err());
+        assert!(shunting_yard("N[1, 2]").is_err());
+        // The offset of anything else is skipped after a single error
+        assert_eq!(shunting_yard("s[1, 2] + 1").unwrap_err().len(), 1);
+    }
+
+    #[test]
+    fn test_blur_and_brightness() {
+        assert_eq!(
+            shunting_yard("k3 - k + b"),
+            Ok(vec![
+                Token::Blur(3),
+                Token::Blur(1),
+                Token::Sub,
+                Token::Brightness(255),
+                Token::Add,
+            ])
+        );
+        assert!(shunting_yard("k0").is_err());
+    }
+
+    #[test]
+    fn test_unary_operators() {
+        assert_eq!(
+            shunting_yard("~(c ^ s) - -h"),
+            Ok(vec![
+                Token::Char('c'),
+                Token::Char('s'),
+                Token::BitXor,
+                Token::Not,
+                Token::Char('h'),
+                Token::Neg,
+                Token::Sub,
+            ])
+        );
+        // Binds tighter than binary operators, looser than swizzles
+        assert_eq!(
+            shunting_yard("~c.r ? 3"),
+            Ok(vec![
+                Token::Char('c'),
+                Token::Swizzle([0, 0, 0]),
+                Token::Not,
+                Token::Num(3),
+                Token::Greater,
+            ])
+        );
+        assert_eq!(
+            shunting_yard("bright(-c, 128)"),
+            Ok(vec![
+                Token::Char('c'),
+                Token::Neg,
+                Token::Num(128),
+                Token::Call(Func::Bright, 2),
+            ])
+        );
+        assert!(shunting_yard("c ~ h").is_err());
+    }
+
+    #[test]
+    fn test_pragmas() {
+        assert_eq!(
+            shunting_yard("use YCbCr; c ^ 55"),
+            Ok(vec![
+                Token::Pragma(Pragma::Space(ColorSpace::YCbCr)),
+                Token::Char('c'),
+                Token::Num(55),
+                Token::BitXor,
+            ])
+        );
+        assert_eq!(
+            shunting_yard("use lab; use saturate; c"),
+            Ok(vec![
+                Token::Pragma(Pragma::Space(ColorSpace::Lab)),
+                Token::Pragma(Pragma::Numeric(NumericMode::Saturate)),
+                Token::Char('c'),
+            ])
+        );
+        assert!(shunting_yard("c; use lab; c").is_err());
+        assert!(shunting_yard("use lab; use hsv; c").is_err());
+        assert!(shunting_yard("use cmyk; c").is_err());
+        assert!(shunting_yard("use lab c").is_err());
+        assert!(shunting_yard("use = 4; c").is_err());
+    }
+
+    #[test]
+    fn test_decimals() {
+        assert_eq!(
+            shunting_yard("c * 0.25"),
+            Ok(vec![
+                Token::Char('c'),
+                Token::Float(0.25f32.to_bits()),
+                Token::Mul
+            ])
+        );
+        // A swizzle after a number is still a swizzle
+        assert_eq!(
+            shunting_yard("2.r"),
+            Ok(vec![Token::Num(2), Token::Swizzle([0, 0, 0])])
+        );
+        // An out of range decimal is one error, not one for each part
+        assert_eq!(shunting_yard("c + 256.5 + 1").unwrap_err().len(), 1);
+        // The float dialect has no upper limit on literals
+        assert_eq!(
+            shunting_yard("use float; x * 1000 + c * 300.5"),
+            Ok(vec![
+                Token::Pragma(Pragma::Numeric(NumericMode::Float)),
+                Token::Char('x'),
+                Token::Float(1000f32.to_bits()),
+                Token::Mul,
+                Token::Char('c'),
+                Token::Float(300.5f32.to_bits()),
+                Token::Mul,
+                Token::Add,
+            ])
+        );
+        assert!(shunting_yard("use wide; c * 1000").is_err());
+    }
+
+    #[test]
+    fn test_params() {
+        assert_eq!(
+            shunting_yard("dist ^ px"),
+            Ok(vec![
+                Token::Param(Param::Dist),
+                Token::Param(Param::PixelX),
+                Token::BitXor,
+            ])
+        );
+        assert!(shunting_yard("width = 4; c").is_err());
+    }
+
+    #[test]
+    fn test_spans() {
+        assert_eq!(
+            shunting_yard_spanned("128 & min(c, x)"),
+            Ok(vec![
+                (Token::Num(128), 0..3),
+                (Token::Char('c'), 10..11),
+                (Token::Char('x'), 13..14),
+                (Token::Call(Func::Min, 2), 6..9),
+                (Token::BitAnd, 4..5),
+            ])
+        );
+        assert_eq!(
+            shunting_yard_spanned("a = r12; a"),
+            Ok(vec![
+                (Token::Random(12), 4..7),
+                (Token::Store(0), 0..1),
+                (Token::Load(0), 9..10),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_error_spans() {
+        assert_eq!(
+            shunting_yard("c + 1000"),
+            Err(vec![GlitchError::NumberOutOfRange { min: 0, span: 4..8 }])
+        );
+        assert_eq!(
+            shunting_yard("k0 + c"),
+            Err(vec![GlitchError::NumberOutOfRange { min: 1, span: 1..2 }])
+        );
+        assert_eq!(
+            shunting_yard("c & (h + 1"),
+            Err(vec![GlitchError::MismatchedParen { span: 4..5 }])
+        );
+        // Spans are in bytes
+        assert_eq!(
+            shunting_yard("c ^ é"),
+            Err(vec![GlitchError::InvalidChar {
+                ch: 'é',
+                span: 4..6
+            }])
+        );
+        assert_eq!(shunting_yard("c + foo").unwrap_err()[0].span(), Some(4..7));
+    }
+
+    #[test]
+    fn test_recovery() {
+        assert_eq!(
+            shunting_yard("c + 1)"),
+            Err(vec![GlitchError::MismatchedParen { span: 5..6 }])
+        );
+        assert_eq!(
+            shunting_yard("c & ()"),
+            Err(vec![GlitchError::EmptyParens { span: 4..6 }])
+        );
+        assert_eq!(
+            shunting_yard("c + * h"),
+            Err(vec![GlitchError::MissingOperand { span: 4..5 }])
+        );
+        assert_eq!(
+            shunting_yard("c lt lt h"),
+            Err(vec![GlitchError::MissingOperand { span: 5..7 }])
+        );
+        assert_eq!(
+            shunting_yard("c (h)"),
+            Err(vec![GlitchError::MissingOperator { span: 2..3 }])
+        );
+        assert_eq!(
+            shunting_yard("min(c,)"),
+            Err(vec![GlitchError::MissingOperand { span: 6..7 }])
+        );
+        // Empty calls are left to `verify`, which knows each function's arity
+        assert_eq!(shunting_yard("min()"), Ok(vec![Token::Call(Func::Min, 0)]));
+
+        // Every problem is reported in one pass, in source order
+        assert_eq!(
+            shunting_yard("(c + ) h) $ 300"),
+            Err(vec![
+                GlitchError::MissingOperand { span: 5..6 },
+                GlitchError::MissingOperator { span: 7..8 },
+                GlitchError::MismatchedParen { span: 8..9 },
+                GlitchError::InvalidChar {
+                    ch: '$',
+                    span: 10..11
+                },
+                GlitchError::NumberOutOfRange {
+                    min: 0,
+                    span: 12..15
+                },
+            ])
+        );
+        // An unknown operator is only reported once
+        assert_eq!(shunting_yard("3$5").unwrap_err().len(), 1);
+    }
 }
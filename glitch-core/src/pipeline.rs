@@ -0,0 +1,351 @@
+use crate::bounds::{find_non_zero_bounds, Bounds};
+use crate::edge::EdgeMode;
+use crate::error::GlitchError;
+use crate::eval::EvalContext;
+use crate::numeric::NumericMode;
+use crate::program::{Program, Scratch};
+use crate::space::ColorSpace;
+use crate::state::StateMode;
+use crate::token::Token;
+use image::{DynamicImage, GenericImage, GenericImageView, Rgba};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::ops::RangeInclusive;
+
+/// Runs a list of compiled expressions over images, each expression reading
+/// the output of the one before it.
+///
+/// Every pixel gets its own random number generator, seeded from `seed` and its
+/// position, so the output only depends on the options and never on how many
+/// threads render it. Parallelism comes from the `rayon` feature.
+///
+/// # Example
+/// ```
+/// use glitch_core::{parser::shunting_yard, Pipeline};
+/// use image::{DynamicImage, Rgba, RgbaImage};
+///
+/// let pipeline = Pipeline {
+///     expressions: vec![shunting_yard("c ^ 255").unwrap()],
+///     seed: 42,
+///     ..Default::default()
+/// };
+///
+/// let img = DynamicImage::from(RgbaImage::from_pixel(8, 8, Rgba([10, 20, 30, 255])));
+/// let out = pipeline.render(img).unwrap();
+/// assert_eq!(out.to_rgba8().get_pixel(7, 7), &Rgba([245, 235, 225, 255]));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Pipeline {
+    /// Compiled expressions (see [`crate::parser::shunting_yard`]), applied in order.
+    pub expressions: Vec<Vec<Token>>,
+    /// Seed for the random operands.
+    pub seed: u64,
+    /// Pixels to evaluate; everything outside is left transparent black.
+    /// Defaults to the bounds of the pixels that aren't transparent black.
+    pub region: Option<Bounds>,
+    /// How `s` carries the previously evaluated pixel.
+    pub state: StateMode,
+    /// How reads outside of the image are resolved.
+    pub edge: EdgeMode,
+    /// Leave fully transparent pixels transparent black instead of evaluating them.
+    pub skip_transparent: bool,
+    /// Colour space expressions run in, unless they pick one with `use <space>;`.
+    pub space: ColorSpace,
+    /// How intermediate values are carried, unless an expression picks a mode with `use <mode>;`.
+    pub numeric: NumericMode,
+}
+
+impl Pipeline {
+    /// Renders a still image.
+    ///
+    /// # Errors
+    /// Returns the evaluation error of the first pixel that fails.
+    pub fn render(&self, image: DynamicImage) -> Result<DynamicImage, GlitchError> {
+        self.render_frame(image, (0, 1), None, |_| {})
+    }
+
+    /// Renders every frame of an animation.
+    ///
+    /// Frames are rendered in parallel, unless an expression reads the previous
+    /// frame with `p`, in which case each frame waits for the one before it.
+    /// `progress` is called with the number of pixels evaluated as they finish.
+    ///
+    /// # Errors
+    /// Returns the evaluation error of the first pixel that fails.
+    pub fn render_frames(
+        &self,
+        frames: Vec<DynamicImage>,
+        progress: impl Fn(u64) + Sync,
+    ) -> Result<Vec<DynamicImage>, GlitchError> {
+        let count = frames.len() as u32;
+
+        if self
+            .expressions
+            .iter()
+            .any(|tokens| crate::reads_previous_frame(tokens))
+        {
+            let mut outputs: Vec<DynamicImage> = Vec::with_capacity(frames.len());
+            for (i, frame) in (0..).zip(frames) {
+                let out = self.render_frame(frame, (i, count), outputs.last(), &progress)?;
+                outputs.push(out);
+            }
+            return Ok(outputs);
+        }
+
+        let frames: Vec<(u32, DynamicImage)> = (0..).zip(frames).collect();
+        par_map(frames, |(i, frame)| {
+            self.render_frame(frame, (i, count), None, &progress)
+        })
+        .into_iter()
+        .collect()
+    }
+
+    /// Renders frame `frame.0` of `frame.1`, with `previous` holding the output
+    /// of the frame before it (read by `p`). `progress` is called with the number
+    /// of pixels evaluated after each expression.
+    ///
+    /// # Errors
+    /// Returns the evaluation error of the first pixel that fails.
+    pub fn render_frame(
+        &self,
+        mut image: DynamicImage,
+        frame: (u32, u32),
+        previous: Option<&DynamicImage>,
+        progress: impl Fn(u64),
+    ) -> Result<DynamicImage, GlitchError> {
+        let (width, height) = image.dimensions();
+        let Some(region) = self.region.or_else(|| find_non_zero_bounds(&image)) else {
+            return Ok(DynamicImage::new(width, height, image.color()));
+        };
+        if width == 0 || height == 0 || region.min_x() >= width || region.min_y() >= height {
+            return Ok(DynamicImage::new(width, height, image.color()));
+        }
+        let columns: Vec<u32> = (region.min_x()..=region.max_x().min(width - 1)).collect();
+        let rows = region.min_y()..=region.max_y().min(height - 1);
+
+        for tokens in &self.expressions {
+            let program = Program::compile(tokens)?;
+            let img = &image;
+            let blur = program.blur_table(img, self.edge, self.space);
+            let eval_pixel = |x: u32, y: u32, saved_rgb: [u8; 3], scratch: &mut Scratch| {
+                let mut rng = StdRng::seed_from_u64(
+                    self.seed.wrapping_add(u64::from(x) << 32 | u64::from(y)),
+                );
+
+                program.eval(
+                    EvalContext {
+                        saved_rgb,
+                        ignore_state: self.state == StateMode::Off,
+                        skip_transparent: self.skip_transparent,
+                        edge: self.edge,
+                        space: self.space,
+                        numeric: self.numeric,
+                        frame,
+                        previous,
+                        blur: blur.as_ref(),
+                        ..EvalContext::at(img, (x, y))
+                    },
+                    img,
+                    &mut rng,
+                    scratch,
+                )
+            };
+            let chained = self.state.is_chained(tokens);
+            // Evaluates a column top to bottom, carrying `s` from `saved_rgb` down it
+            // when chained
+            let column = |x: u32, saved_rgb: &mut [u8; 3]| -> Result<Vec<Rgba<u8>>, GlitchError> {
+                let mut scratch = Scratch::default();
+                rows.clone()
+                    .map(|y| {
+                        let rgba = eval_pixel(x, y, *saved_rgb, &mut scratch)?;
+                        if chained {
+                            *saved_rgb = [rgba[0], rgba[1], rgba[2]];
+                        }
+                        Ok(rgba)
+                    })
+                    .collect()
+            };
+
+            // `s` chains each pixel to the one evaluated before it. Unless the chain
+            // restarts on every column, it runs through the whole image in go-glitch's
+            // order: down each column, from the left column to the right.
+            let results: Vec<Result<Vec<Rgba<u8>>, GlitchError>> =
+                if chained && self.state == StateMode::Scan {
+                    let mut saved_rgb = [0, 0, 0];
+                    columns.iter().map(|&x| column(x, &mut saved_rgb)).collect()
+                } else {
+                    par_map(columns.clone(), |x| column(x, &mut [0, 0, 0]))
+                };
+
+            let mut output = DynamicImage::new(width, height, image.color());
+            for (&x, pixels) in columns.iter().zip(results) {
+                for (y, rgba) in rows.clone().zip(pixels?) {
+                    output.put_pixel(x, y, rgba);
+                }
+            }
+
+            progress(span(&rows) * columns.len() as u64);
+            image = output;
+        }
+
+        Ok(image)
+    }
+}
+
+const fn span(range: &RangeInclusive<u32>) -> u64 {
+    (*range.end() - *range.start()) as u64 + 1
+}
+
+/// Maps `items` in parallel when the `rayon` feature is enabled, keeping their order.
+#[cfg(feature = "rayon")]
+fn par_map<T: Send, U: Send>(items: Vec<T>, f: impl Fn(T) -> U + Sync + Send) -> Vec<U> {
+    use rayon::prelude::*;
+    items.into_par_iter().map(f).collect()
+}
+
+#[cfg(not(feature = "rayon"))]
+fn par_map<T, U>(items: Vec<T>, f: impl Fn(T) -> U) -> Vec<U> {
+    items.into_iter().map(f).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::shunting_yard;
+    use image::RgbaImage;
+
+    fn pipeline(expressions: &[&str]) -> Pipeline {
+        Pipeline {
+            expressions: expressions
+                .iter()
+                .map(|e| shunting_yard(e).unwrap())
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// A 5x3 image where every pixel is a different color.
+    fn gradient() -> DynamicImage {
+        RgbaImage::from_fn(5, 3, |x, y| {
+            Rgba([x as u8 * 40 + 1, y as u8 * 70 + 3, 99, 255])
+        })
+        .into()
+    }
+
+    fn rgb(img: &DynamicImage, x: u32, y: u32) -> [u8; 3] {
+        let [r, g, b, _] = img.get_pixel(x, y).0;
+        [r, g, b]
+    }
+
+    #[test]
+    fn test_expressions_chain() {
+        let out = pipeline(&["c + 1", "c * 2"]).render(gradient()).unwrap();
+        // 161 + 1 and 143 + 1 overflow when doubled
+        assert_eq!(rgb(&out, 4, 2), [68, 32, 200]);
+    }
+
+    #[test]
+    fn test_scan_state() {
+        let img = gradient();
+        let out = pipeline(&["(c ^ s) + 1"]).render(img.clone()).unwrap();
+
+        // `s` runs down each column, then on to the top of the next one
+        let mut saved = [0u8; 3];
+        for x in 0..5 {
+            for y in 0..3 {
+                let expected = [0, 1, 2].map(|i| (rgb(&img, x, y)[i] ^ saved[i]).wrapping_add(1));
+                assert_eq!(rgb(&out, x, y), expected, "({x}, {y})");
+                saved = expected;
+            }
+        }
+    }
+
+    #[test]
+    fn test_readme_state_examples() {
+        // The README examples that read `s`, written out with wrapping `u8`
+        // operators and go-glitch's precedence, and evaluated in go-glitch's
+        // order: x outer, y inner, with `s` the previous result starting from black.
+        let img = gradient();
+        let check = |source: &str, reference: fn(u8, u8) -> u8| {
+            let out = pipeline(&[source]).render(img.clone()).unwrap();
+
+            let mut saved = [0u8; 3];
+            for x in 0..5 {
+                for y in 0..3 {
+                    let c = rgb(&img, x, y);
+                    let expected = [0, 1, 2].map(|i| reference(c[i], saved[i]));
+                    assert_eq!(rgb(&out, x, y), expected, "{source} at ({x}, {y})");
+                    saved = expected;
+                }
+            }
+        };
+
+        check("128 & (c - ((c - 150 + s) > 5 < s))", |c, s| {
+            let shifted = (c.wrapping_sub(150).wrapping_add(s) >> 5).wrapping_shl(s.into());
+            128 & c.wrapping_sub(shifted)
+        });
+        check("128 & (c + 255) : (s ^ (c ^ 255)) + 25", |c, s| {
+            (128 & c.wrapping_add(255) & !(s ^ (c ^ 255))).wrapping_add(25)
+        });
+    }
+
+    #[test]
+    fn test_column_state() {
+        let img = gradient();
+        let mut columns = pipeline(&["(c ^ s) + 1"]);
+        columns.state = StateMode::Columns;
+        let out = columns.render(img.clone()).unwrap();
+
+        for x in 0..5 {
+            let first = rgb(&img, x, 0).map(|v| v.wrapping_add(1));
+            assert_eq!(rgb(&out, x, 0), first);
+        }
+        // Further down, `s` comes from the pixel above
+        let above = rgb(&out, 2, 0);
+        let expected = [0, 1, 2].map(|i| (rgb(&img, 2, 1)[i] ^ above[i]).wrapping_add(1));
+        assert_eq!(rgb(&out, 2, 1), expected);
+
+        let mut off = pipeline(&["c + s"]);
+        off.state = StateMode::Off;
+        assert_eq!(off.render(img.clone()).unwrap(), img);
+    }
+
+    #[test]
+    fn test_region() {
+        let mut invert = pipeline(&["c ^ 255"]);
+        invert.region = Some(Bounds::from_corners((1, 1), (2, 9)));
+        let out = invert.render(gradient()).unwrap();
+
+        assert_eq!(out.get_pixel(0, 1), Rgba([0, 0, 0, 0]));
+        assert_eq!(out.get_pixel(3, 2), Rgba([0, 0, 0, 0]));
+        assert_eq!(rgb(&out, 2, 2), [255 - 81, 255 - 143, 255 - 99]);
+
+        // Fully transparent images have nothing to evaluate
+        let empty: DynamicImage = RgbaImage::new(3, 3).into();
+        assert_eq!(pipeline(&["c ^ 255"]).render(empty.clone()).unwrap(), empty);
+    }
+
+    #[test]
+    fn test_frames_read_previous_output() {
+        let frames = vec![gradient(), gradient(), gradient()];
+        let out = pipeline(&["p + 1"]).render_frames(frames, |_| {}).unwrap();
+
+        // The first frame reads its own input
+        let [r, g, b] = rgb(&gradient(), 1, 1);
+        for (i, frame) in (1..).zip(&out) {
+            assert_eq!(rgb(frame, 1, 1), [r + i, g + i, b + i]);
+        }
+    }
+
+    #[test]
+    fn test_progress_counts_pixels() {
+        let done = std::sync::atomic::AtomicU64::new(0);
+        let frames = vec![gradient(), gradient()];
+        pipeline(&["c + 1", "c - 1"])
+            .render_frames(frames, |n| {
+                done.fetch_add(n, std::sync::atomic::Ordering::Relaxed);
+            })
+            .unwrap();
+        assert_eq!(done.into_inner(), 2 * 2 * 15);
+    }
+}
@@ -0,0 +1,538 @@
+use crate::ast::generated_name;
+use crate::blur::BlurTable;
+use crate::edge::EdgeMode;
+use crate::error::GlitchError;
+use crate::eval::{self, binary_op, unary_op, EvalContext};
+use crate::numeric::{Lane, NumericMode};
+use crate::rgb::Rgb;
+use crate::space::ColorSpace;
+use crate::token::{self, Func, Param, Pragma, Token};
+use image::{DynamicImage, Rgba};
+use rand::RngCore;
+
+/// Values that are computed at most once per pixel and shared by every use in
+/// a [`Program`]. Most read the neighbourhood of the pixel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Feature {
+    /// `Y`, the luminance of the pixel.
+    Luma,
+    /// `h`, the pixel mirrored horizontally.
+    Horizontal,
+    /// `v`, the pixel mirrored vertically.
+    Vertical,
+    /// `d`, the pixel mirrored both ways.
+    Diagonal,
+    /// `e`, the difference across the 3x3 neighbourhood.
+    Edge,
+    /// `H`, the highest values of the 3x3 neighbourhood.
+    Highest,
+    /// `L`, the lowest values of the 3x3 neighbourhood.
+    Lowest,
+    /// `k<n>`, the average of the box of radius `n`.
+    Blur(u8),
+    /// `t`, a random pixel close by.
+    Nearby,
+    /// `g`, a random pixel from anywhere in the image.
+    Anywhere,
+    /// `r<n>`, a random pixel within `n` of the pixel.
+    Random(u8),
+}
+
+impl Feature {
+    /// Whether computing the value draws random numbers. Such values are drawn
+    /// again for every use when state is off.
+    pub const fn draws_random(self) -> bool {
+        matches!(self, Self::Nearby | Self::Anywhere | Self::Random(_))
+    }
+}
+
+/// A binary operator resolved to its lane operation for every lane type.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct BinaryOp {
+    byte: fn(u8, u8) -> u8,
+    wide: fn(i32, i32) -> i32,
+    float: fn(f32, f32) -> f32,
+}
+
+impl BinaryOp {
+    fn resolve(tok: &Token) -> Option<Self> {
+        Some(Self {
+            byte: binary_op(tok)?,
+            wide: binary_op(tok)?,
+            float: binary_op(tok)?,
+        })
+    }
+}
+
+/// A prefix operator resolved to its lane operation for every lane type.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct UnaryOp {
+    byte: fn(u8) -> u8,
+    wide: fn(i32) -> i32,
+    float: fn(f32) -> f32,
+}
+
+impl UnaryOp {
+    fn resolve(tok: &Token) -> Option<Self> {
+        Some(Self {
+            byte: unary_op(tok)?,
+            wide: unary_op(tok)?,
+            float: unary_op(tok)?,
+        })
+    }
+}
+
+/// A token with everything that doesn't depend on the pixel resolved.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Op {
+    Num(u8),
+    Float(f32),
+    /// A feature, by its index in [`Program::features`].
+    Feature(usize),
+    Current,
+    Alpha,
+    Saved,
+    Previous,
+    X,
+    Y,
+    Noise,
+    Brightness(u8),
+    Invert,
+    /// `R`, `G` or `B`: a value in one channel.
+    Color(usize, u8),
+    Param(Param),
+    Binary(BinaryOp),
+    /// `-` or `~`.
+    Unary(UnaryOp),
+    Sample(char),
+    Swizzle([usize; 3]),
+    If,
+    Call(Func, usize),
+    Store(usize),
+    Load(usize),
+    Output(usize),
+}
+
+/// An expression compiled for evaluation, ready to be run on every pixel.
+///
+/// Compiling checks the tokens once, so evaluating a pixel doesn't match on
+/// tokens or allocate: the stack, bindings and features live in a [`Scratch`]
+/// that is reused from pixel to pixel.
+///
+/// # Example
+/// ```
+/// use glitch_core::parser::shunting_yard;
+/// use glitch_core::program::{Feature, Program};
+///
+/// let program = Program::compile(&shunting_yard("h ^ (Y + k2) - h").unwrap()).unwrap();
+/// assert_eq!(program.max_depth(), 3);
+/// assert_eq!(
+///     program.features(),
+///     [Feature::Horizontal, Feature::Luma, Feature::Blur(2)]
+/// );
+/// ```
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub(crate) ops: Vec<Op>,
+    max_depth: usize,
+    slots: usize,
+    features: Vec<Feature>,
+    /// Colour space picked with `use <space>;`.
+    pub(crate) space: Option<ColorSpace>,
+    /// Numeric mode picked with `use <mode>;`.
+    pub(crate) numeric: Option<NumericMode>,
+}
+
+impl Program {
+    /// Compiles RPN tokens, as returned by [`crate::parser::shunting_yard`].
+    ///
+    /// # Errors
+    /// Returns [`GlitchError::Eval`] if the tokens are malformed, which
+    /// [`crate::verify`] rules out.
+    pub fn compile(tokens: &[Token]) -> Result<Self, GlitchError> {
+        let mut program = Self {
+            ops: Vec::with_capacity(tokens.len()),
+            max_depth: 0,
+            slots: 0,
+            features: Vec::new(),
+            space: None,
+            numeric: None,
+        };
+        for pragma in token::pragmas(tokens) {
+            match pragma {
+                Pragma::Space(space) => program.space = Some(space),
+                Pragma::Numeric(numeric) => program.numeric = Some(numeric),
+            }
+        }
+
+        let mut depth = 0;
+        let mut has_outputs = false;
+        // Slots stored to so far, so loads can't read a slot before it's assigned
+        let mut bound = [false; 256];
+        for tok in tokens {
+            let (pops, pushes) = crate::stack_effect(tok);
+            if depth < pops as usize {
+                return Err(GlitchError::eval("Stack underflow"));
+            }
+
+            let op = match *tok {
+                Token::Num(n) => Op::Num(n),
+                Token::Float(bits) => Op::Float(f32::from_bits(bits)),
+                Token::Char(c) => match c {
+                    'c' => Op::Current,
+                    'A' => Op::Alpha,
+                    's' => Op::Saved,
+                    'p' => Op::Previous,
+                    'x' => Op::X,
+                    'y' => Op::Y,
+                    'N' => Op::Noise,
+                    'Y' => program.feature(Feature::Luma),
+                    'h' => program.feature(Feature::Horizontal),
+                    'v' => program.feature(Feature::Vertical),
+                    'd' => program.feature(Feature::Diagonal),
+                    'e' => program.feature(Feature::Edge),
+                    'H' => program.feature(Feature::Highest),
+                    'L' => program.feature(Feature::Lowest),
+                    't' => program.feature(Feature::Nearby),
+                    'g' => program.feature(Feature::Anywhere),
+                    _ => return Err(GlitchError::eval(format!("Unexpected token: {:?}", c))),
+                },
+                Token::Random(num) => program.feature(Feature::Random(num)),
+                Token::Blur(radius) => program.feature(Feature::Blur(radius)),
+                Token::Brightness(value) => Op::Brightness(value),
+                Token::Invert => Op::Invert,
+                Token::RGBColor((part, value)) => match part {
+                    'R' => Op::Color(0, value),
+                    'G' => Op::Color(1, value),
+                    'B' => Op::Color(2, value),
+                    _ => return Err(GlitchError::eval(format!("Unexpected token: {:?}", part))),
+                },
+                Token::Param(param) => Op::Param(param),
+                Token::Sample(source) if crate::parser::is_sampleable(source) => Op::Sample(source),
+                Token::Sample(source) => {
+                    return Err(GlitchError::eval(format!(
+                        "Operand '{}' can't be sampled at an offset",
+                        source
+                    )))
+                }
+                Token::Swizzle(channels) => Op::Swizzle(channels.map(|c| c as usize % 3)),
+                Token::If => Op::If,
+                Token::Call(func, args) if func.accepts(args) => Op::Call(func, args as usize),
+                Token::Call(func, args) => {
+                    return Err(GlitchError::eval(format!(
+                        "Function '{}' expects {} but got {}",
+                        func.name(),
+                        func.arity_description(),
+                        args
+                    )))
+                }
+                Token::Store(slot) => {
+                    bound[slot as usize] = true;
+                    program.slots = program.slots.max(slot as usize + 1);
+                    Op::Store(slot as usize)
+                }
+                Token::Load(slot) if bound[slot as usize] => Op::Load(slot as usize),
+                // Without the source, bindings are named like `Ast::from_tokens` does
+                Token::Load(slot) => {
+                    return Err(GlitchError::eval(format!(
+                        "Binding '{}' used before assignment",
+                        generated_name(slot as usize)
+                    )))
+                }
+                Token::Output(channel @ 0..=3) => {
+                    has_outputs = true;
+                    Op::Output(channel as usize)
+                }
+                Token::Pragma(_) => continue,
+                _ => {
+                    if let Some(op) = BinaryOp::resolve(tok) {
+                        Op::Binary(op)
+                    } else if let Some(op) = UnaryOp::resolve(tok) {
+                        Op::Unary(op)
+                    } else {
+                        return Err(GlitchError::eval(format!("Unexpected token: {:?}", tok)));
+                    }
+                }
+            };
+            program.ops.push(op);
+
+            depth = depth - pops as usize + pushes as usize;
+            program.max_depth = program.max_depth.max(depth);
+        }
+
+        // Channels without a statement take the final value
+        if depth == 0 && !has_outputs {
+            return Err(GlitchError::eval("Stack underflow"));
+        }
+        Ok(program)
+    }
+
+    /// Op reading `feature`, which is added to the features if it's new.
+    fn feature(&mut self, feature: Feature) -> Op {
+        let index = self.features.iter().position(|f| *f == feature);
+        Op::Feature(index.unwrap_or_else(|| {
+            self.features.push(feature);
+            self.features.len() - 1
+        }))
+    }
+
+    /// Largest number of values on the stack at once.
+    pub const fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    /// Number of binding slots.
+    pub const fn slots(&self) -> usize {
+        self.slots
+    }
+
+    /// Features read by the program, in order of first use.
+    pub fn features(&self) -> &[Feature] {
+        &self.features
+    }
+
+    /// Box sums of `input` for every blur the program reads, or `None` if it
+    /// doesn't blur. Built once per image and passed in [`EvalContext::blur`].
+    pub fn blur_table(
+        &self,
+        input: &DynamicImage,
+        edge: EdgeMode,
+        space: ColorSpace,
+    ) -> Option<BlurTable> {
+        let radius = self
+            .features
+            .iter()
+            .filter_map(|feature| match feature {
+                Feature::Blur(radius) => Some(*radius),
+                _ => None,
+            })
+            .max()?;
+        Some(BlurTable::new(
+            input,
+            edge,
+            self.space.unwrap_or(space),
+            radius,
+        ))
+    }
+
+    /// Evaluates the program for a single pixel, reusing the buffers of `scratch`.
+    ///
+    /// # Errors
+    /// Returns [`GlitchError::Eval`] if evaluation fails, which compiling rules out.
+    pub fn eval<R: RngCore>(
+        &self,
+        ctx: EvalContext<'_>,
+        input: &DynamicImage,
+        rng: &mut R,
+        scratch: &mut Scratch,
+    ) -> Result<Rgba<u8>, GlitchError> {
+        match self.numeric.unwrap_or(ctx.numeric) {
+            NumericMode::Byte => eval::run::<u8, R>(self, ctx, input, rng, scratch),
+            NumericMode::Wide | NumericMode::Saturate => {
+                eval::run::<i32, R>(self, ctx, input, rng, scratch)
+            }
+            NumericMode::Float => eval::run::<f32, R>(self, ctx, input, rng, scratch),
+        }
+        .map_err(GlitchError::eval)
+    }
+}
+
+/// Buffers for evaluating [`Program`]s, reused from pixel to pixel.
+#[derive(Debug, Default)]
+pub struct Scratch {
+    bytes: Buffers<u8>,
+    wide: Buffers<i32>,
+    float: Buffers<f32>,
+    /// Value of each feature, once computed for the pixel.
+    features: Vec<Option<Rgb>>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Buffers<L> {
+    pub(crate) stack: Vec<[L; 3]>,
+    pub(crate) slots: Vec<[L; 3]>,
+}
+
+/// Lanes with their own buffers in a [`Scratch`] and their own resolved operators.
+pub(crate) trait Buffered: Lane {
+    /// The buffers for this lane and the feature cache, emptied and sized for
+    /// `program`.
+    fn buffers<'a>(
+        scratch: &'a mut Scratch,
+        program: &Program,
+    ) -> (&'a mut Buffers<Self>, &'a mut [Option<Rgb>]) {
+        let (buffers, features) = Self::split(scratch);
+        buffers.stack.clear();
+        buffers.stack.reserve(program.max_depth);
+        buffers.slots.clear();
+        buffers.slots.resize(program.slots, [Self::default(); 3]);
+        features.clear();
+        features.resize(program.features.len(), None);
+        (buffers, features)
+    }
+
+    fn split(scratch: &mut Scratch) -> (&mut Buffers<Self>, &mut Vec<Option<Rgb>>);
+    fn binary(op: BinaryOp) -> fn(Self, Self) -> Self;
+    fn unary(op: UnaryOp) -> fn(Self) -> Self;
+}
+
+impl Buffered for u8 {
+    fn split(scratch: &mut Scratch) -> (&mut Buffers<Self>, &mut Vec<Option<Rgb>>) {
+        (&mut scratch.bytes, &mut scratch.features)
+    }
+
+    fn binary(op: BinaryOp) -> fn(Self, Self) -> Self {
+        op.byte
+    }
+
+    fn unary(op: UnaryOp) -> fn(Self) -> Self {
+        op.byte
+    }
+}
+
+impl Buffered for i32 {
+    fn split(scratch: &mut Scratch) -> (&mut Buffers<Self>, &mut Vec<Option<Rgb>>) {
+        (&mut scratch.wide, &mut scratch.features)
+    }
+
+    fn binary(op: BinaryOp) -> fn(Self, Self) -> Self {
+        op.wide
+    }
+
+    fn unary(op: UnaryOp) -> fn(Self) -> Self {
+        op.wide
+    }
+}
+
+impl Buffered for f32 {
+    fn split(scratch: &mut Scratch) -> (&mut Buffers<Self>, &mut Vec<Option<Rgb>>) {
+        (&mut scratch.float, &mut scratch.features)
+    }
+
+    fn binary(op: BinaryOp) -> fn(Self, Self) -> Self {
+        op.float
+    }
+
+    fn unary(op: UnaryOp) -> fn(Self) -> Self {
+        op.float
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::shunting_yard;
+    use image::{GenericImageView, RgbaImage};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn compile(expr: &str) -> Program {
+        Program::compile(&shunting_yard(expr).unwrap()).unwrap()
+    }
+
+    fn context(img: &DynamicImage, position: (u32, u32), ignore_state: bool) -> EvalContext<'_> {
+        EvalContext {
+            ignore_state,
+            ..EvalContext::at(img, position)
+        }
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        let err = |tokens: &[Token]| Program::compile(tokens).unwrap_err().to_string();
+        assert_eq!(err(&[Token::Num(1), Token::Add]), "Stack underflow");
+        assert_eq!(err(&[]), "Stack underflow");
+        assert_eq!(
+            err(&[Token::Load(0), Token::Num(1), Token::Store(0)]),
+            "Binding '_a' used before assignment"
+        );
+        // Slot 0 was never stored, even though a later slot was
+        assert_eq!(
+            err(&[Token::Num(1), Token::Store(1), Token::Load(0)]),
+            "Binding '_a' used before assignment"
+        );
+        assert_eq!(
+            err(&[Token::Num(0), Token::Num(0), Token::Sample('x')]),
+            "Operand 'x' can't be sampled at an offset"
+        );
+    }
+
+    #[test]
+    fn test_features_shared() {
+        let program = compile("t + r3 * k1 + t * r3 * r4");
+        assert_eq!(
+            program.features(),
+            [
+                Feature::Nearby,
+                Feature::Random(3),
+                Feature::Blur(1),
+                Feature::Random(4)
+            ]
+        );
+        assert_eq!(program.max_depth(), 3);
+
+        let program = compile("_a = c + 1; _b = _a * 2; _b - _a");
+        assert_eq!(program.slots(), 2);
+        assert!(program.features().is_empty());
+    }
+
+    #[test]
+    fn test_scratch_reuse() {
+        let img: DynamicImage =
+            RgbaImage::from_fn(6, 5, |x, y| Rgba([(x * 40) as u8, (y * 50) as u8, 7, 255])).into();
+        let programs = [
+            compile("c ^ (t + g) - r2 & e | H - L + k1"),
+            compile("use wide; _a = Y * 2; _a > 255 ? h : v - d"),
+            compile("use float; avg(c, h, v) * 1.5"),
+        ];
+
+        // One scratch shared by every pixel and program gives the same result
+        // as a fresh one each time
+        let mut scratch = Scratch::default();
+        for program in &programs {
+            for (x, y, _) in img.pixels() {
+                let reused = program
+                    .eval(
+                        context(&img, (x, y), false),
+                        &img,
+                        &mut StdRng::seed_from_u64(3),
+                        &mut scratch,
+                    )
+                    .unwrap();
+                let fresh = program
+                    .eval(
+                        context(&img, (x, y), false),
+                        &img,
+                        &mut StdRng::seed_from_u64(3),
+                        &mut Scratch::default(),
+                    )
+                    .unwrap();
+                assert_eq!(reused, fresh, "{:?} at ({}, {})", program.ops, x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn test_random_features_with_state() {
+        let img: DynamicImage =
+            RgbaImage::from_fn(8, 8, |x, y| Rgba([(x * 30) as u8, (y * 30) as u8, 1, 255])).into();
+        let program = compile("(t - t) | (g ^ g) | (r5 - r5)");
+        let mut scratch = Scratch::default();
+        let mut rng = StdRng::seed_from_u64(9);
+
+        // With state, every use reads the same draw
+        let out = program
+            .eval(context(&img, (4, 4), false), &img, &mut rng, &mut scratch)
+            .unwrap();
+        assert_eq!(out.0, [0, 0, 0, 255]);
+
+        // Without it, each use draws again
+        let differs = (0..8).any(|_| {
+            let out = program
+                .eval(context(&img, (4, 4), true), &img, &mut rng, &mut scratch)
+                .unwrap();
+            out.0 != [0, 0, 0, 255]
+        });
+        assert!(differs);
+    }
+}
@@ -1,4 +1,4 @@
-#[derive(Debug, Clone, Copy, Default)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub struct Rgb {
     pub r: u8,
     pub g: u8,
@@ -33,6 +33,12 @@ impl From<[u8; 3]> for Rgb {
     }
 }
 
+impl From<Rgb> for [u8; 3] {
+    fn from(rgb: Rgb) -> Self {
+        [rgb.r, rgb.g, rgb.b]
+    }
+}
+
 impl From<Rgb> for image::Rgb<u8> {
     fn from(rgb: Rgb) -> Self {
         Self([rgb.r, rgb.g, rgb.b])
@@ -0,0 +1,208 @@
+use crate::eval::{hsv_to_rgb, rgb_to_hsv};
+use serde::{Deserialize, Serialize};
+
+/// Colour space an expression is evaluated in.
+///
+/// Every pixel read from the image is converted into the space before the
+/// expression sees it, and the result is converted back to RGB afterwards.
+/// Operators stay channel-wise, so `r`, `g` and `b` address the first, second
+/// and third component of the space (e.g. Y, Cb and Cr). Alpha is untouched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub enum ColorSpace {
+    #[default]
+    Rgb,
+    /// Hue (wrapping over the whole byte), saturation and value.
+    Hsv,
+    /// Full-range BT.601 luma and chroma, as used by JPEG.
+    YCbCr,
+    /// CIE L*a*b* (D65), with L* scaled to `0..=255` and a*, b* offset by 128.
+    Lab,
+}
+
+impl ColorSpace {
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Rgb => "rgb",
+            Self::Hsv => "hsv",
+            Self::YCbCr => "ycbcr",
+            Self::Lab => "lab",
+        }
+    }
+
+    /// Converts an RGB color into this space.
+    pub fn from_rgb(self, [r, g, b]: [u8; 3]) -> [u8; 3] {
+        match self {
+            Self::Rgb => [r, g, b],
+            Self::Hsv => {
+                let (h, s, v) = rgb_to_hsv(r, g, b);
+                [
+                    ((h * 256.0).round() as u32 % 256) as u8,
+                    to_byte(s * 255.0),
+                    to_byte(v * 255.0),
+                ]
+            }
+            Self::YCbCr => {
+                let [y, cb, cr] = transform(RGB_TO_YCBCR, [r, g, b].map(f64::from));
+                [to_byte(y), to_byte(cb + 128.0), to_byte(cr + 128.0)]
+            }
+            Self::Lab => {
+                let linear = [r, g, b].map(|v| srgb_to_linear(f64::from(v) / 255.0));
+                let xyz = transform(RGB_TO_XYZ, linear);
+                let [fx, fy, fz] = [0, 1, 2].map(|i| lab_f(xyz[i] / WHITE[i]));
+                [
+                    to_byte(116f64.mul_add(fy, -16.0) * 255.0 / 100.0),
+                    to_byte(500f64.mul_add(fx - fy, 128.0)),
+                    to_byte(200f64.mul_add(fy - fz, 128.0)),
+                ]
+            }
+        }
+    }
+
+    /// Converts a color in this space back into RGB.
+    pub fn to_rgb(self, [c0, c1, c2]: [u8; 3]) -> [u8; 3] {
+        match self {
+            Self::Rgb => [c0, c1, c2],
+            Self::Hsv => hsv_to_rgb(
+                f64::from(c0) / 256.0,
+                f64::from(c1) / 255.0,
+                f64::from(c2) / 255.0,
+            )
+            .into(),
+            Self::YCbCr => {
+                let ycbcr = [f64::from(c0), f64::from(c1) - 128.0, f64::from(c2) - 128.0];
+                transform(YCBCR_TO_RGB, ycbcr).map(to_byte)
+            }
+            Self::Lab => {
+                let fy = (f64::from(c0) * 100.0 / 255.0 + 16.0) / 116.0;
+                let fx = fy + (f64::from(c1) - 128.0) / 500.0;
+                let fz = fy - (f64::from(c2) - 128.0) / 200.0;
+                let xyz = [fx, fy, fz];
+                let xyz = [0, 1, 2].map(|i| lab_f_inv(xyz[i]) * WHITE[i]);
+                transform(XYZ_TO_RGB, xyz).map(|v| to_byte(linear_to_srgb(v) * 255.0))
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for ColorSpace {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "rgb" => Ok(Self::Rgb),
+            "hsv" => Ok(Self::Hsv),
+            "ycbcr" => Ok(Self::YCbCr),
+            "lab" => Ok(Self::Lab),
+            _ => Err(format!(
+                "Unknown colour space '{}' (expected rgb, hsv, ycbcr or lab)",
+                s
+            )),
+        }
+    }
+}
+
+const RGB_TO_YCBCR: [[f64; 3]; 3] = [
+    [0.299, 0.587, 0.114],
+    [-0.168_736, -0.331_264, 0.5],
+    [0.5, -0.418_688, -0.081_312],
+];
+const YCBCR_TO_RGB: [[f64; 3]; 3] = [
+    [1.0, 0.0, 1.402],
+    [1.0, -0.344_136, -0.714_136],
+    [1.0, 1.772, 0.0],
+];
+/// Linear sRGB to CIE XYZ and back.
+const RGB_TO_XYZ: [[f64; 3]; 3] = [
+    [0.412_456_4, 0.357_576_1, 0.180_437_5],
+    [0.212_672_9, 0.715_152_2, 0.072_175_0],
+    [0.019_333_9, 0.119_192_0, 0.950_304_1],
+];
+const XYZ_TO_RGB: [[f64; 3]; 3] = [
+    [3.240_454_2, -1.537_138_5, -0.498_531_4],
+    [-0.969_266_0, 1.876_010_8, 0.041_556_0],
+    [0.055_643_4, -0.204_025_9, 1.057_225_2],
+];
+/// D65 reference white.
+const WHITE: [f64; 3] = [0.950_47, 1.0, 1.088_83];
+const DELTA: f64 = 6.0 / 29.0;
+
+fn transform(matrix: [[f64; 3]; 3], v: [f64; 3]) -> [f64; 3] {
+    matrix.map(|row| row[0].mul_add(v[0], row[1].mul_add(v[1], row[2] * v[2])))
+}
+
+const fn to_byte(v: f64) -> u8 {
+    v.round().clamp(0.0, 255.0) as u8
+}
+
+fn srgb_to_linear(v: f64) -> f64 {
+    if v <= 0.040_45 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(v: f64) -> f64 {
+    if v <= 0.003_130_8 {
+        v * 12.92
+    } else {
+        1.055f64.mul_add(v.powf(1.0 / 2.4), -0.055)
+    }
+}
+
+fn lab_f(t: f64) -> f64 {
+    if t > DELTA.powi(3) {
+        t.cbrt()
+    } else {
+        t / (3.0 * DELTA * DELTA) + 4.0 / 29.0
+    }
+}
+
+fn lab_f_inv(t: f64) -> f64 {
+    if t > DELTA {
+        t.powi(3)
+    } else {
+        3.0 * DELTA * DELTA * (t - 4.0 / 29.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPACES: [ColorSpace; 4] = [
+        ColorSpace::Rgb,
+        ColorSpace::Hsv,
+        ColorSpace::YCbCr,
+        ColorSpace::Lab,
+    ];
+
+    #[test]
+    fn test_round_trip() {
+        // Conversions quantize to bytes, so allow some drift (Lab loses the most
+        // in dark, saturated colors)
+        for space in SPACES {
+            for rgb in [[0, 0, 0], [255, 255, 255], [200, 40, 120], [12, 180, 90]] {
+                let back = space.to_rgb(space.from_rgb(rgb));
+                for (a, b) in rgb.iter().zip(back) {
+                    assert!(a.abs_diff(b) <= 6, "{space:?}: {rgb:?} -> {back:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_known_values() {
+        assert_eq!(ColorSpace::YCbCr.from_rgb([255, 255, 255]), [255, 128, 128]);
+        assert_eq!(ColorSpace::YCbCr.from_rgb([0, 0, 0]), [0, 128, 128]);
+        assert_eq!(ColorSpace::Lab.from_rgb([255, 255, 255]), [255, 128, 128]);
+        assert_eq!(ColorSpace::Hsv.from_rgb([0, 0, 255]), [171, 255, 255]);
+    }
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!("YCbCr".parse(), Ok(ColorSpace::YCbCr));
+        assert_eq!("lab".parse(), Ok(ColorSpace::Lab));
+        assert!("cmyk".parse::<ColorSpace>().is_err());
+    }
+}
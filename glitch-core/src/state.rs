@@ -0,0 +1,67 @@
+use crate::token::Token;
+
+/// How the saved pixel `s` is carried from one evaluated pixel to the next.
+///
+/// Pixels are always seeded from their position, so every mode gives the same
+/// output regardless of how many threads run it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StateMode {
+    /// `s` is always black and nothing is cached between operands of a pixel.
+    Off,
+    /// `s` is the previous output pixel in go-glitch's scan order: down each
+    /// column, from the left column to the right. Expressions that read `s` run
+    /// on a single thread.
+    #[default]
+    Scan,
+    /// `s` is the previous output pixel in the same column and starts black on
+    /// every column, so columns run in parallel.
+    Columns,
+}
+
+impl StateMode {
+    /// Whether `tokens` have to be evaluated along the chain of this mode, i.e.
+    /// state is enabled and the expression reads `s`. Otherwise every pixel is
+    /// independent and may be evaluated in any order.
+    pub fn is_chained(self, tokens: &[Token]) -> bool {
+        self != Self::Off && tokens.iter().any(|t| matches!(t, Token::Char('s')))
+    }
+}
+
+impl std::str::FromStr for StateMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "off" => Ok(Self::Off),
+            "scan" => Ok(Self::Scan),
+            "columns" => Ok(Self::Columns),
+            _ => Err(format!(
+                "Unknown state mode '{}' (expected off, scan or columns)",
+                s
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::shunting_yard;
+
+    #[test]
+    fn test_is_chained() {
+        let reads_s = shunting_yard("128 & (c - ((c - 150 + s) > 5 < s))").unwrap();
+        let no_s = shunting_yard("(c & (c ^ 55)) + 25").unwrap();
+
+        assert!(StateMode::Scan.is_chained(&reads_s));
+        assert!(StateMode::Columns.is_chained(&reads_s));
+        assert!(!StateMode::Off.is_chained(&reads_s));
+        assert!(!StateMode::Scan.is_chained(&no_s));
+    }
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!("Columns".parse(), Ok(StateMode::Columns));
+        assert!("wavefront".parse::<StateMode>().is_err());
+    }
+}
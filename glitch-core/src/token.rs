@@ -1,14 +1,20 @@
 use std::fmt::Formatter;
 use serde::{Deserialize, Serialize};
+use crate::numeric::NumericMode;
+use crate::space::ColorSpace;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Copy, Serialize, Deserialize)]
 pub enum Token {
     // -- Literals and values
     Num(u8),
+    // Decimal literal, stored as the bits of an `f32`
+    Float(u32),
     Random(u8),
     Brightness(u8),
+    Blur(u8),
     RGBColor((char, u8)),
     Char(char),
+    Param(Param),
 
     // -- Arithmetic operators
     Add,
@@ -26,9 +32,42 @@ pub enum Token {
     BitLShift,
     BitRShift,
     Invert,
+    Not,
+
+    // -- Unary arithmetic
+    Neg,
 
     // -- Comparison
     Greater,
+    Less,
+    GreaterEqual,
+    LessEqual,
+    Equal,
+    NotEqual,
+    Min,
+    Max,
+
+    // -- Offset sampling: `src[dx, dy]`
+    Sample(char),
+
+    // -- Swizzle: source channel index for each of red, green and blue
+    Swizzle([u8; 3]),
+
+    // -- Conditional: `if(cond, then, else)`
+    If,
+
+    // -- Function call with its argument count
+    Call(Func, u8),
+
+    // -- Bindings (slot index)
+    Store(u8),
+    Load(u8),
+
+    // -- Per-channel output (0 = red, 1 = green, 2 = blue, 3 = alpha)
+    Output(u8),
+
+    // -- Expression-wide setting: `use <name>;`
+    Pragma(Pragma),
 
     // -- Other symbols / markers
     Weight,
@@ -36,12 +75,201 @@ pub enum Token {
     RightParen,
 }
 
+/// Expression-wide settings, selected by `use <name>;` statements at the start
+/// of an expression.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Copy, Serialize, Deserialize)]
+pub enum Pragma {
+    Space(ColorSpace),
+    Numeric(NumericMode),
+}
+
+/// Pragmas at the start of a token list.
+pub fn pragmas(tokens: &[Token]) -> impl Iterator<Item = Pragma> + '_ {
+    tokens.iter().map_while(|tok| match tok {
+        Token::Pragma(pragma) => Some(*pragma),
+        _ => None,
+    })
+}
+
+/// Label of an output channel as written in a per-channel statement (`r:`, `g:`, `b:`, `a:`).
+pub const fn channel_label(channel: u8) -> char {
+    match channel {
+        0 => 'r',
+        1 => 'g',
+        2 => 'b',
+        3 => 'a',
+        _ => '?',
+    }
+}
+
+/// Positional operands and image constants spelled as words.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Copy, Serialize, Deserialize)]
+pub enum Param {
+    /// Distance from the centre of the image, full scale at the corners.
+    Dist,
+    /// Angle around the centre of the image, wrapping like hue.
+    Angle,
+    /// Pixel column, modulo 256.
+    PixelX,
+    /// Pixel row, modulo 256.
+    PixelY,
+    Width,
+    Height,
+    /// Index of the current animation frame.
+    Frame,
+    /// Number of frames in the animation, 1 for still images.
+    Frames,
+    /// Time through the animation, going once around the unit range so it loops.
+    Time,
+}
+
+impl Param {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "dist" => Some(Self::Dist),
+            "angle" => Some(Self::Angle),
+            "px" => Some(Self::PixelX),
+            "py" => Some(Self::PixelY),
+            "width" => Some(Self::Width),
+            "height" => Some(Self::Height),
+            "frame" => Some(Self::Frame),
+            "frames" => Some(Self::Frames),
+            "T" => Some(Self::Time),
+            _ => None,
+        }
+    }
+
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Dist => "dist",
+            Self::Angle => "angle",
+            Self::PixelX => "px",
+            Self::PixelY => "py",
+            Self::Width => "width",
+            Self::Height => "height",
+            Self::Frame => "frame",
+            Self::Frames => "frames",
+            Self::Time => "T",
+        }
+    }
+
+    /// Whether the value changes from pixel to pixel.
+    pub const fn is_positional(&self) -> bool {
+        matches!(
+            self,
+            Self::Dist | Self::Angle | Self::PixelX | Self::PixelY
+        )
+    }
+}
+
+/// Built-in functions callable as `name(arg, ...)`.
+///
+/// Functions work channel-wise and treat `0..=255` as the unit range, so
+/// `sqrt(128)` is `181` rather than `11`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Copy, Serialize, Deserialize)]
+pub enum Func {
+    Abs,
+    Clamp,
+    Mix,
+    Sin,
+    Cos,
+    Sqrt,
+    Avg,
+    Min,
+    Max,
+    Bright,
+    Hue,
+    Sat,
+    Val,
+    Hsv,
+    Atan,
+    Hypot,
+}
+
+impl Func {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "abs" => Some(Self::Abs),
+            "clamp" => Some(Self::Clamp),
+            "mix" => Some(Self::Mix),
+            "sin" => Some(Self::Sin),
+            "cos" => Some(Self::Cos),
+            "sqrt" => Some(Self::Sqrt),
+            "avg" => Some(Self::Avg),
+            "min" => Some(Self::Min),
+            "max" => Some(Self::Max),
+            "bright" => Some(Self::Bright),
+            "hue" => Some(Self::Hue),
+            "sat" => Some(Self::Sat),
+            "val" => Some(Self::Val),
+            "hsv" => Some(Self::Hsv),
+            "atan" => Some(Self::Atan),
+            "hypot" => Some(Self::Hypot),
+            _ => None,
+        }
+    }
+
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Abs => "abs",
+            Self::Clamp => "clamp",
+            Self::Mix => "mix",
+            Self::Sin => "sin",
+            Self::Cos => "cos",
+            Self::Sqrt => "sqrt",
+            Self::Avg => "avg",
+            Self::Min => "min",
+            Self::Max => "max",
+            Self::Bright => "bright",
+            Self::Hue => "hue",
+            Self::Sat => "sat",
+            Self::Val => "val",
+            Self::Hsv => "hsv",
+            Self::Atan => "atan",
+            Self::Hypot => "hypot",
+        }
+    }
+
+    /// Minimum and maximum (`None` for variadic) number of arguments.
+    pub const fn arity(&self) -> (u8, Option<u8>) {
+        match self {
+            Self::Abs
+            | Self::Sin
+            | Self::Cos
+            | Self::Sqrt
+            | Self::Hue
+            | Self::Sat
+            | Self::Val => (1, Some(1)),
+            Self::Bright | Self::Atan | Self::Hypot => (2, Some(2)),
+            Self::Clamp | Self::Mix | Self::Hsv => (3, Some(3)),
+            Self::Avg | Self::Min | Self::Max => (2, None),
+        }
+    }
+
+    pub const fn accepts(&self, args: u8) -> bool {
+        match self.arity() {
+            (min, Some(max)) => args >= min && args <= max,
+            (min, None) => args >= min,
+        }
+    }
+
+    /// Human-readable arity, e.g. "3 arguments" or "at least 2 arguments".
+    pub fn arity_description(&self) -> String {
+        match self.arity() {
+            (1, Some(1)) => "1 argument".to_string(),
+            (min, Some(max)) if min == max => format!("{} arguments", min),
+            (min, Some(max)) => format!("{} to {} arguments", min, max),
+            (min, None) => format!("at least {} arguments", min),
+        }
+    }
+}
+
 impl std::fmt::Display for Token {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
         match self {
             Self::Char(ch) => match ch {
                 'c' => f.write_str("Current Pixel Value"),
-                'b' => f.write_str("Blurred"),
+                'A' => f.write_str("Alpha"),
                 'h' => f.write_str("Horizontal"),
                 'v' => f.write_str("Vertical"),
                 'd' => f.write_str("Diagonal"),
@@ -51,6 +279,7 @@ impl std::fmt::Display for Token {
                 'G' => f.write_str("Green"),
                 'B' => f.write_str("Blue"),
                 's' => f.write_str("Previous Saved Pixel Value"),
+                'p' => f.write_str("Previous Frame"),
                 't' => f.write_str("Random Color in 6x6 Grid"),
                 'g' => f.write_str("Random Color in the Entire Image"),
                 'x' => f.write_str("X Coordinate"),
@@ -60,6 +289,18 @@ impl std::fmt::Display for Token {
                 _ => write!(f, "{:?}", self),
             },
             Self::Num(n) => write!(f, "Num({})", n),
+            Self::Float(bits) => write!(f, "Num({})", f32::from_bits(*bits)),
+            Self::Param(param) => f.write_str(match param {
+                Param::Dist => "Distance from Centre",
+                Param::Angle => "Angle around Centre",
+                Param::PixelX => "Pixel X",
+                Param::PixelY => "Pixel Y",
+                Param::Width => "Image Width",
+                Param::Height => "Image Height",
+                Param::Frame => "Frame Index",
+                Param::Frames => "Frame Count",
+                Param::Time => "Animation Time",
+            }),
             Self::BitAnd => f.write_str("Bitwise AND"),
             Self::BitAndNot => f.write_str("Bitwise AND NOT"),
             Self::BitOr => f.write_str("Bitwise OR"),
@@ -73,11 +314,42 @@ impl std::fmt::Display for Token {
             Self::Mod => f.write_str("Modulus"),
             Self::Pow => f.write_str("Power"),
             Self::Greater => f.write_str("Greater"),
+            Self::Less => f.write_str("Less"),
+            Self::GreaterEqual => f.write_str("Greater or Equal"),
+            Self::LessEqual => f.write_str("Less or Equal"),
+            Self::Equal => f.write_str("Equal"),
+            Self::NotEqual => f.write_str("Not Equal"),
+            Self::Min => f.write_str("Minimum"),
+            Self::Max => f.write_str("Maximum"),
             Self::Weight => f.write_str("Weight"),
             Self::Invert => f.write_str("Invert"),
+            Self::Not => f.write_str("Bitwise NOT"),
+            Self::Neg => f.write_str("Negate"),
             Self::Random(range) => write!(f, "Random color grid - {range}x{range}"),
             Self::RGBColor((part, val)) => write!(f, "RGB Color - {part}: {val}"),
             Self::Brightness(val) => write!(f, "Brightness - {val}"),
+            Self::Blur(radius) => write!(f, "Box Blur - radius {radius}"),
+            Self::Swizzle(channels) => {
+                let names: String = channels
+                    .iter()
+                    .map(|&c| ['r', 'g', 'b'][c as usize % 3])
+                    .collect();
+                write!(f, "Swizzle .{names}")
+            }
+            Self::Sample(source) => write!(f, "Sample {source} at Offset"),
+            Self::If => f.write_str("Conditional"),
+            Self::Call(func, args) => write!(f, "Function {}/{}", func.name(), args),
+            Self::Store(slot) => write!(f, "Store Binding #{slot}"),
+            Self::Output(channel) => match channel {
+                0 => f.write_str("Output Red"),
+                1 => f.write_str("Output Green"),
+                2 => f.write_str("Output Blue"),
+                3 => f.write_str("Output Alpha"),
+                _ => write!(f, "Output Channel #{channel}"),
+            },
+            Self::Load(slot) => write!(f, "Load Binding #{slot}"),
+            Self::Pragma(Pragma::Space(space)) => write!(f, "Use Colour Space {}", space.name()),
+            Self::Pragma(Pragma::Numeric(mode)) => write!(f, "Use Numeric Mode {}", mode.name()),
             _ => write!(f, "{:?}", self),
         }
     }